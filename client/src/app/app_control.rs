@@ -60,6 +60,9 @@ impl App {
             } else {
                 self.appstate = AppState::RoomConnected;
                 self.waiting.waiting_room_state = WaitingRoomState::Normal;
+                if let Some(room) = self.room.as_mut() {
+                    room.tick().await;
+                }
             }
             match &self.appstate {
                 AppState::Closed => {
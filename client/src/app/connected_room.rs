@@ -1,4 +1,9 @@
-use std::{sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
     Err,
@@ -16,14 +21,138 @@ use tokio::{
     task,
 };
 
+// The server forgets about typing after a few seconds, so it gets told again well before that
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+// Messages the server has not acknowledged by then are shown as failed
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Only set on lines this client sent during the session
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendStatus {
+    Pending(Instant),
+    Sent,
+    Failed,
+    // Turned away by the server with the reason, sending it again would not help
+    Rejected(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatLine {
+    // Server side message id, missing for direct messages
+    pub id: Option<String>,
+    pub seq: Option<i64>,
+    pub text: String,
+    pub reactions: BTreeMap<String, Vec<String>>,
+    pub reply_to: Option<String>,
+    pub reply_count: u32,
+    pub client_id: Option<String>,
+    pub status: Option<SendStatus>,
+    pub mentions_me: bool,
+}
+
+impl ChatLine {
+    // Shown by this client alone, the server never sees it
+    pub fn note(text: String) -> ChatLine {
+        ChatLine {
+            id: None,
+            seq: None,
+            text: format!("-- {text}"),
+            reactions: BTreeMap::new(),
+            reply_to: None,
+            reply_count: 0,
+            client_id: None,
+            status: None,
+            mentions_me: false,
+        }
+    }
+
+    // Replies are only shown in the thread pane, everything else belongs to the main list
+    pub fn is_reply(&self) -> bool {
+        self.reply_to.is_some()
+    }
+
+    pub fn in_thread(&self, root: &str) -> bool {
+        self.id.as_deref() == Some(root) || self.reply_to.as_deref() == Some(root)
+    }
+
+    pub fn toggle_reaction(&mut self, emoji: String, account: String, added: bool) {
+        let accounts = self.reactions.entry(emoji.clone()).or_default();
+        accounts.retain(|a| *a != account);
+        if added {
+            accounts.push(account);
+        }
+        if accounts.is_empty() {
+            self.reactions.remove(&emoji);
+        }
+    }
+
+    /*
+     * Compact "emoji count" summary shown under the message, empty when nobody reacted
+     */
+    pub fn reaction_summary(&self) -> String {
+        self.reactions
+            .iter()
+            .map(|(emoji, accounts)| format!("{emoji} {}", accounts.len()))
+            .collect::<Vec<String>>()
+            .join("  ")
+    }
+}
+
+// What the server says this user can post in the room
+#[derive(Debug, Clone)]
+pub struct RoomMode {
+    pub can_post: bool,
+    pub slow_mode_secs: Option<u32>,
+}
+
+impl Default for RoomMode {
+    fn default() -> RoomMode {
+        RoomMode {
+            can_post: true,
+            slow_mode_secs: None,
+        }
+    }
+}
+
+impl RoomMode {
+    pub fn input_title(&self) -> String {
+        match (self.can_post, self.slow_mode_secs) {
+            (false, _) => "Read only, only moderators can post".to_string(),
+            (true, Some(secs)) => format!("Input (slow mode, one message every {secs}s)"),
+            (true, None) => "Input".to_string(),
+        }
+    }
+}
+
+fn ring_bell() {
+    let mut stdout = std::io::stdout();
+    stdout.write_all(b"\x07").unwrap_or_default();
+    stdout.flush().unwrap_or_default();
+}
+
 #[derive(Debug)]
 pub struct Room {
     room_id: String,
-    messages: Arc<Mutex<Vec<String>>>,
+    messages: Arc<Mutex<Vec<ChatLine>>>,
+    // Names of the other members currently typing
+    typing: Arc<Mutex<Vec<String>>>,
+    // When the server was last told this user is typing, None while they are not
+    typing_sent_at: Option<Instant>,
+    // Names of the other members to the last sequence number they have read
+    read_markers: Arc<Mutex<BTreeMap<String, i64>>>,
+    mode: Arc<Mutex<RoomMode>>,
+    // Last sequence number this client told the server it has read
+    read_sent: i64,
+    username: String,
+    // Content of messages sent but not acknowledged yet, by client id, kept for retrying
+    outbox: HashMap<String, String>,
+    sent_count: u64,
     // This handles the user input and cursor movement to accurately depict what the user is going to do
     character_indx: usize,
     input_mode: InputMode,
     input: String,
+    // Id of the message starting the thread open in the side pane
+    thread: Option<String>,
     user_input_sx: Sender<String>,
     closing_room_sx: tokio::sync::watch::Sender<bool>,
 }
@@ -44,7 +173,15 @@ impl Room {
     ) -> Result<Room, Err> {
         let (user_input_sx, user_input_rx) = mpsc::channel(100);
         let (server_message_sx, mut server_message_rx) = mpsc::channel::<Response>(100);
-        let url = format!("ws://{url}/ws/joinroom?room_id={room_id}&username={username}");
+        // Moderators sign in with their API token, everyone else only gives a name
+        let token = std::env::var("API_TOKEN")
+            .map(|token| format!("&token={token}"))
+            .unwrap_or_default();
+        let url = websocket_function::websocket_url(
+            &url,
+            &format!("/ws/joinroom?room_id={room_id}&username={username}{token}"),
+        );
+        let username = username.to_string();
 
         // println!("Connecting to {}", url);
 
@@ -58,18 +195,153 @@ impl Room {
             .await.unwrap();
         });
 
-        let messages = Arc::new(Mutex::new(Vec::new()));
+        let messages = Arc::new(Mutex::new(Vec::<ChatLine>::new()));
         let clone_messsages = Arc::clone(&messages);
+        let typing = Arc::new(Mutex::new(Vec::<String>::new()));
+        let clone_typing = Arc::clone(&typing);
+        let read_markers = Arc::new(Mutex::new(BTreeMap::<String, i64>::new()));
+        let clone_read_markers = Arc::clone(&read_markers);
+        let mode = Arc::new(Mutex::new(RoomMode::default()));
+        let clone_mode = Arc::clone(&mode);
+        let clone_username = username.clone();
+        let clone_room_id = room_id.clone();
         tokio::spawn(async move {
             while let Some(msg) = server_message_rx.recv().await {
                 // Display the derived message here
-                if msg.sender.is_none() || msg.content.is_none() {
+                if !msg.is_readable() {
                     break;
                 }
-                let sender = msg.sender.unwrap();
-                let message = msg.content.unwrap();
+                if msg.event_type.as_deref() == Some("read_receipt") {
+                    let user = msg.user.unwrap_or_default();
+                    let seq = msg.seq.unwrap_or_default();
+                    clone_read_markers.lock().await.insert(user, seq);
+                    continue;
+                }
+                if msg.event_type.as_deref() == Some("room_mode") {
+                    *clone_mode.lock().await = RoomMode {
+                        can_post: msg.can_post.unwrap_or(true),
+                        slow_mode_secs: msg.slow_mode_secs,
+                    };
+                    continue;
+                }
+                if msg.event_type.as_deref() == Some("mention") {
+                    ring_bell();
+                    // Mentions in this room already show up highlighted as the message arrives
+                    if msg.room_id.as_deref() != Some(clone_room_id.as_str()) {
+                        let room = msg.room_id.as_deref().unwrap_or_default();
+                        clone_messsages.lock().await.push(ChatLine {
+                            id: None,
+                            seq: None,
+                            text: format!(
+                                "[mention in {room}] {}:{}",
+                                msg.sender.as_deref().unwrap_or_default(),
+                                msg.content.as_deref().unwrap_or_default()
+                            ),
+                            reactions: BTreeMap::new(),
+                            reply_to: None,
+                            reply_count: 0,
+                            client_id: None,
+                            status: None,
+                            mentions_me: true,
+                        });
+                    }
+                    continue;
+                }
+                if msg.event_type.as_deref() == Some("rejected") {
+                    let mut lock_message = clone_messsages.lock().await;
+                    if let Some(existing) = lock_message.iter_mut().find(|existing| {
+                        existing.client_id.is_some() && existing.client_id == msg.client_id
+                    }) {
+                        existing.status = Some(SendStatus::Rejected(msg.reason.unwrap_or_default()));
+                    }
+                    continue;
+                }
+                if msg.event_type.as_deref() == Some("ack") {
+                    let mut lock_message = clone_messsages.lock().await;
+                    if let Some(existing) = lock_message.iter_mut().find(|existing| {
+                        existing.client_id.is_some() && existing.client_id == msg.client_id
+                    }) {
+                        existing.status = Some(SendStatus::Sent);
+                        existing.id = msg.id;
+                        existing.seq = msg.seq;
+                    }
+                    continue;
+                }
+                if msg.event_type.as_deref() == Some("typing") {
+                    let user = msg.user.unwrap_or_default();
+                    let mut lock_typing = clone_typing.lock().await;
+                    lock_typing.retain(|name| *name != user);
+                    if msg.active == Some(true) {
+                        lock_typing.push(user);
+                    }
+                    continue;
+                }
+                // Whoever just sent something is clearly done typing it
+                if let Some(sender) = &msg.sender {
+                    clone_typing.lock().await.retain(|name| name != sender);
+                }
+
                 let mut lock_message = clone_messsages.lock().await;
-                lock_message.push(format!("{sender}:{message}"));
+                if msg.event_type.as_deref() == Some("thread_updated") {
+                    if let Some(existing) = lock_message
+                        .iter_mut()
+                        .find(|existing| existing.id.is_some() && existing.id == msg.message_id)
+                    {
+                        existing.reply_count = msg.reply_count.unwrap_or_default();
+                    }
+                    continue;
+                }
+                if msg.event_type.as_deref() == Some("reaction") {
+                    if let Some(existing) = lock_message
+                        .iter_mut()
+                        .find(|existing| existing.id.is_some() && existing.id == msg.message_id)
+                    {
+                        existing.toggle_reaction(
+                            msg.emoji.unwrap_or_default(),
+                            msg.account.unwrap_or_default(),
+                            msg.added.unwrap_or_default(),
+                        );
+                    }
+                    continue;
+                }
+
+                let line = ChatLine {
+                    id: msg.id.clone(),
+                    seq: msg.seq,
+                    text: msg.display_line(),
+                    reactions: msg.reactions.unwrap_or_default(),
+                    reply_to: msg.reply_to,
+                    reply_count: msg.reply_count.unwrap_or_default(),
+                    client_id: msg.client_id.clone(),
+                    status: None,
+                    mentions_me: msg
+                        .mentions
+                        .as_ref()
+                        .is_some_and(|mentions| mentions.contains(&clone_username)),
+                };
+                match msg.event_type.as_deref() {
+                    // Redraw the line that is already on screen instead of adding a new one
+                    Some("message_edited") | Some("message_deleted") => {
+                        if let Some(existing) = lock_message
+                            .iter_mut()
+                            .find(|existing| existing.id.is_some() && existing.id == line.id)
+                        {
+                            *existing = line;
+                        }
+                    }
+                    _ => match lock_message.iter_mut().find(|existing| {
+                        existing.client_id.is_some() && existing.client_id == line.client_id
+                    }) {
+                        // The server's copy of a message this client is showing as pending
+                        Some(existing) => {
+                            *existing = ChatLine {
+                                status: Some(SendStatus::Sent),
+                                ..line
+                            }
+                        }
+                        None => lock_message.push(line),
+                    },
+                }
                 drop(lock_message);
             }
         });
@@ -77,9 +349,18 @@ impl Room {
         let room = Room {
             room_id,
             messages,
+            typing,
+            typing_sent_at: None,
+            read_markers,
+            mode,
+            read_sent: 0,
+            username,
+            outbox: HashMap::new(),
+            sent_count: 0,
             character_indx: 0,
             input_mode: InputMode::Normal,
             input: "".to_string(),
+            thread: None,
             user_input_sx,
             closing_room_sx,
         };
@@ -121,10 +402,230 @@ impl Room {
         self.character_indx = 0;
     }
 
+    /*
+     * Called every time the app goes round its loop
+     */
+    pub async fn tick(&mut self) {
+        self.report_read().await;
+        self.expire_pending().await;
+    }
+
+    /*
+     * The room always shows the newest messages, so everything received has been seen. Only
+     * talks to the server when there is news.
+     */
+    async fn report_read(&mut self) {
+        let newest = self
+            .messages
+            .lock()
+            .await
+            .iter()
+            .filter_map(|line| line.seq)
+            .max()
+            .unwrap_or_default();
+        if newest <= self.read_sent {
+            return;
+        }
+        let event = serde_json::json!({"type": "read", "seq": newest}).to_string();
+        self.user_input_sx
+            .send(event)
+            .await
+            .unwrap_or_else(|e| println!("Unable to send read marker because of {e}"));
+        self.read_sent = newest;
+    }
+
+    async fn expire_pending(&mut self) {
+        let mut messages = self.messages.lock().await;
+        for line in messages.iter_mut() {
+            match (&line.status, &line.client_id) {
+                (Some(SendStatus::Pending(sent_at)), _) if sent_at.elapsed() > ACK_TIMEOUT => {
+                    line.status = Some(SendStatus::Failed);
+                }
+                // Nothing left to retry once the server has it or turned it away
+                (Some(SendStatus::Sent | SendStatus::Rejected(_)), Some(client_id)) => {
+                    self.outbox.remove(client_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn next_client_id(&mut self) -> String {
+        self.sent_count += 1;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        format!("{millis}-{}", self.sent_count)
+    }
+
+    async fn send_with_client_id(&mut self, client_id: &str, content: &str) -> bool {
+        let event =
+            serde_json::json!({"type": "send", "client_id": client_id, "content": content});
+        self.user_input_sx.send(event.to_string()).await.is_ok()
+    }
+
+    /*
+     * Shows the message straight away as pending, it is swapped for the server's copy once
+     * that comes back
+     */
+    async fn send_tracked(&mut self, content: String) {
+        let client_id = self.next_client_id();
+        let status = match self.send_with_client_id(&client_id, &content).await {
+            true => SendStatus::Pending(Instant::now()),
+            false => SendStatus::Failed,
+        };
+        self.messages.lock().await.push(ChatLine {
+            id: None,
+            seq: None,
+            text: format!("{}:{content}", self.username),
+            reactions: BTreeMap::new(),
+            reply_to: None,
+            reply_count: 0,
+            client_id: Some(client_id.clone()),
+            status: Some(status),
+            mentions_me: false,
+        });
+        self.outbox.insert(client_id, content);
+    }
+
+    /*
+     * Sends failed messages again under the same client id, so the server drops any that did
+     * get through the first time
+     */
+    async fn retry_failed(&mut self) {
+        let failed: Vec<String> = self
+            .messages
+            .lock()
+            .await
+            .iter()
+            .filter(|line| line.status == Some(SendStatus::Failed))
+            .filter_map(|line| line.client_id.clone())
+            .collect();
+        for client_id in failed {
+            let Some(content) = self.outbox.get(&client_id).cloned() else {
+                continue;
+            };
+            if !self.send_with_client_id(&client_id, &content).await {
+                continue;
+            }
+            if let Some(line) = self
+                .messages
+                .lock()
+                .await
+                .iter_mut()
+                .find(|line| line.client_id.as_deref() == Some(client_id.as_str()))
+            {
+                line.status = Some(SendStatus::Pending(Instant::now()));
+            }
+        }
+    }
+
+    async fn send_typing(&mut self, active: bool) {
+        let event = serde_json::json!({"type": "typing", "active": active}).to_string();
+        self.user_input_sx
+            .send(event)
+            .await
+            .unwrap_or_else(|e| println!("Unable to send typing because of {e}"));
+        self.typing_sent_at = active.then(Instant::now);
+    }
+
+    async fn refresh_typing(&mut self) {
+        let typing = !self.input.is_empty();
+        match self.typing_sent_at {
+            Some(sent_at) if typing && sent_at.elapsed() < TYPING_REFRESH => {}
+            Some(_) => self.send_typing(typing).await,
+            None if typing => self.send_typing(true).await,
+            None => {}
+        }
+    }
+
+    async fn stop_typing(&mut self) {
+        if self.typing_sent_at.is_some() {
+            self.send_typing(false).await;
+        }
+    }
+
+    /*
+     * Lets /edit, /delete, /react and /reply point at a message by the number shown next to it, which gets
+     * swapped for the id the server knows the message by
+     */
+    async fn resolve_message_index(&self, input: &str) -> String {
+        let mut parts = input.splitn(3, ' ');
+        let (Some(command), Some(index)) = (parts.next(), parts.next()) else {
+            return input.to_string();
+        };
+        if !["/edit", "/delete", "/react", "/reply"].contains(&command) {
+            return input.to_string();
+        }
+        let Ok(index) = index.parse::<usize>() else {
+            return input.to_string();
+        };
+
+        let messages = self.messages.lock().await;
+        match messages.get(index).and_then(|line| line.id.as_ref()) {
+            Some(id) => match parts.next() {
+                Some(rest) => format!("{command} {id} {rest}"),
+                None => format!("{command} {id}"),
+            },
+            None => input.to_string(),
+        }
+    }
+
+    /*
+     * Opens the thread of message n in the side pane. Picking a reply opens the thread it is in
+     */
+    async fn open_thread(&mut self, index: &str) {
+        let Ok(index) = index.trim().parse::<usize>() else {
+            return;
+        };
+        let messages = self.messages.lock().await;
+        let root = messages
+            .get(index)
+            .and_then(|line| line.reply_to.clone().or_else(|| line.id.clone()));
+        drop(messages);
+        if root.is_some() {
+            self.thread = root;
+        }
+    }
+
     async fn submit_message(&mut self) {
-        // I cba deal with th lifetimes clone for now
+        // Thread commands only change what this client shows so they never reach the server
+        if let Some(index) = self.input.strip_prefix("/thread ") {
+            let index = index.to_string();
+            self.open_thread(&index).await;
+            self.input.clear();
+            self.reset_cursor();
+            return;
+        }
+        if self.input == "/close" {
+            self.thread = None;
+            self.input.clear();
+            self.reset_cursor();
+            return;
+        }
+        // Commands still go through, moderators need them to open the room up again
+        if !self.input.starts_with('/') && !self.mode.lock().await.can_post {
+            self.messages.lock().await.push(ChatLine::note(
+                "This room is read only, only moderators can post".to_string(),
+            ));
+            return;
+        }
+
+        let input = match &self.thread {
+            // Plain text typed while a thread is open is posted into it
+            Some(root) if !self.input.starts_with('/') => format!("/reply {root} {}", self.input),
+            None if !self.input.starts_with('/') && !self.input.is_empty() => {
+                let content = self.input.clone();
+                self.send_tracked(content).await;
+                self.input.clear();
+                self.reset_cursor();
+                return;
+            }
+            _ => self.resolve_message_index(&self.input).await,
+        };
         self.user_input_sx
-            .send(self.input.clone())
+            .send(input)
             .await
             .unwrap_or_else(|e| println!("Unable to send message because of {e}"));
         self.input.clear();
@@ -148,18 +649,30 @@ impl Room {
         match self.input_mode {
             InputMode::Normal => match key.code {
                 KeyCode::Char('e') => self.input_mode = InputMode::Editing,
+                KeyCode::Char('c') => self.thread = None,
+                KeyCode::Char('r') => self.retry_failed().await,
                 KeyCode::Char('q') => return AppAction::GoToWaitingRoom,
                 _ => {}
             },
             InputMode::Editing => match key.code {
                 KeyCode::Char(new_char) => {
                     self.enter_char(new_char);
+                    self.refresh_typing().await;
+                }
+                KeyCode::Enter => {
+                    self.stop_typing().await;
+                    self.submit_message().await;
+                }
+                KeyCode::Backspace => {
+                    self.delete_char();
+                    self.refresh_typing().await;
                 }
-                KeyCode::Enter => self.submit_message().await,
-                KeyCode::Backspace => self.delete_char(),
                 KeyCode::Left => self.move_cursor_left(),
                 KeyCode::Right => self.move_cursor_right(),
-                KeyCode::Esc => self.input_mode = InputMode::Normal,
+                KeyCode::Esc => {
+                    self.stop_typing().await;
+                    self.input_mode = InputMode::Normal;
+                }
                 _ => {}
             },
         }
@@ -171,7 +684,23 @@ impl Room {
             let guard = tokio::runtime::Handle::current().block_on(self.messages.lock());
             guard.clone()
         });
-        let messages = Messages::new(&self.input_mode, &msg, &self.room_id, &self.input);
+        let typing = task::block_in_place(|| {
+            let guard = tokio::runtime::Handle::current().block_on(self.typing.lock());
+            guard.clone()
+        });
+        let read_markers = task::block_in_place(|| {
+            let guard = tokio::runtime::Handle::current().block_on(self.read_markers.lock());
+            guard.clone()
+        });
+        let input_title = task::block_in_place(|| {
+            let guard = tokio::runtime::Handle::current().block_on(self.mode.lock());
+            guard.input_title()
+        });
+        let messages = Messages::new(&self.input_mode, &msg, &self.room_id, &self.input)
+            .with_input_title(&input_title)
+            .with_thread(self.thread.as_deref())
+            .with_typing(&typing)
+            .with_read_markers(&read_markers);
         messages.render(rect, f.buffer_mut());
         drop(msg);
     }
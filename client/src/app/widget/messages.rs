@@ -1,22 +1,34 @@
+use std::collections::BTreeMap;
+
 use ratatui::{
     buffer::Buffer,
     layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Text},
     widgets::{Block, Borders, Paragraph, Widget},
 };
 
-use crate::app::connected_room::InputMode;
+use crate::app::connected_room::{ChatLine, InputMode, SendStatus};
 
 pub struct Messages<'input_mode, 'messages, 'room_id, 'input> {
     input_mode: &'input_mode InputMode,
-    messages: &'messages Vec<String>,
+    messages: &'messages Vec<ChatLine>,
     room_id: &'room_id str,
     input: &'input str,
+    // Id of the message starting the thread shown in the side pane
+    thread: Option<&'messages str>,
+    // Names of the other members currently typing
+    typing: &'messages [String],
+    // Names of the other members to the last sequence number they have read
+    read_markers: Option<&'messages BTreeMap<String, i64>>,
+    // Tells whether the user can post and how often
+    input_title: &'messages str,
 }
 
 impl<'input_mode, 'messages, 'room_id, 'input> Messages<'input_mode, 'messages, 'room_id, 'input> {
     pub fn new(
         input_mode: &'input_mode InputMode,
-        messages: &'messages Vec<String>,
+        messages: &'messages Vec<ChatLine>,
         room_id: &'room_id str,
         input: &'input str,
     ) -> Messages<'input_mode, 'messages, 'room_id, 'input>
@@ -30,7 +42,99 @@ impl<'input_mode, 'messages, 'room_id, 'input> Messages<'input_mode, 'messages,
             messages,
             room_id,
             input,
+            thread: None,
+            typing: &[],
+            read_markers: None,
+            input_title: "Input",
+        }
+    }
+
+    pub fn with_input_title(mut self, input_title: &'messages str) -> Self {
+        self.input_title = input_title;
+        self
+    }
+
+    pub fn with_read_markers(mut self, read_markers: &'messages BTreeMap<String, i64>) -> Self {
+        self.read_markers = Some(read_markers);
+        self
+    }
+
+    pub fn with_typing(mut self, typing: &'messages [String]) -> Self {
+        self.typing = typing;
+        self
+    }
+
+    pub fn with_thread(mut self, thread: Option<&'messages str>) -> Self {
+        self.thread = thread;
+        self
+    }
+}
+
+/*
+ * Turns the lines into rows, keeping the number each line has in the full list so commands
+ * can point at it, and cuts it down to the newest rows that fit in the given height
+ */
+fn typing_line(typing: &[String]) -> String {
+    match typing {
+        [] => String::new(),
+        [one] => format!("{one} is typing…"),
+        [one, two] => format!("{one} and {two} are typing…"),
+        _ => format!("{} people are typing…", typing.len()),
+    }
+}
+
+fn visible_rows<'a>(
+    lines: impl Iterator<Item = (usize, &'a ChatLine)>,
+    height: u16,
+    show_reply_count: bool,
+    read_markers: Option<&BTreeMap<String, i64>>,
+) -> Vec<Line<'static>> {
+    // Reactions take up a row of their own under the message they belong to
+    let mut rows: Vec<Line<'static>> = Vec::new();
+    for (i, m) in lines {
+        let status = match &m.status {
+            Some(SendStatus::Pending(_)) => " (sending…)".to_string(),
+            Some(SendStatus::Sent) => " ✓".to_string(),
+            Some(SendStatus::Failed) => " (failed, press r to retry)".to_string(),
+            Some(SendStatus::Rejected(reason)) => format!(" (not sent: {reason})"),
+            None => String::new(),
+        };
+        let row = if show_reply_count && m.reply_count > 0 {
+            format!("{i}: {}{status} [{} replies]", m.text, m.reply_count)
+        } else {
+            format!("{i}: {}{status}", m.text)
+        };
+        if m.mentions_me {
+            rows.push(Line::styled(
+                row,
+                Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+            ));
+        } else {
+            rows.push(Line::from(row));
         }
+        let summary = m.reaction_summary();
+        if !summary.is_empty() {
+            rows.push(Line::from(format!("    {summary}")));
+        }
+        // Members are listed under the last message they have read
+        if let (Some(seq), Some(read_markers)) = (m.seq, read_markers) {
+            let seen_by: Vec<&str> = read_markers
+                .iter()
+                .filter(|(_, read)| **read == seq)
+                .map(|(name, _)| name.as_str())
+                .collect();
+            if !seen_by.is_empty() {
+                rows.push(Line::from(format!("    seen by {}", seen_by.join(", "))));
+            }
+        }
+    }
+
+    if rows.len() > height as usize {
+        rows[rows.len() - height as usize..].to_vec()
+    } else {
+        rows
     }
 }
 
@@ -43,39 +147,71 @@ impl<'input_mode, 'messages, 'room_id, 'input> Widget
                 Constraint::Length(1),
                 Constraint::Length(3),
                 Constraint::Min(1),
+                Constraint::Length(1),
             ]
         });
 
-        let [help_area, input_area, message_area] = vertical.areas(area);
+        let [help_area, input_area, message_area, typing_area] = vertical.areas(area);
 
         Paragraph::new(match self.input_mode {
-            InputMode::Editing => "Press escape to return to normal mode",
-            InputMode::Normal => "Press e to edit. Press q to join a different room",
+            InputMode::Editing => {
+                "Press escape to return to normal mode. /thread <n> opens a thread. /edit <n> <text>, /delete <n>, /react <n> <emoji>"
+            }
+            InputMode::Normal => {
+                "Press e to edit. Press c to close the thread. Press r to retry failed messages. Press q to join a different room"
+            }
         })
         .render(help_area, buf);
 
-        let available_height = message_area.height.saturating_sub(2);
-
-        let messages: Vec<String> = self
-            .messages
-            .iter()
-            .enumerate()
-            .map(|(i, m)| format!("{i}: {m}"))
-            .collect();
-
-        let visible_messages = if messages.len() > available_height as usize {
-            messages[messages.len() - available_height as usize..].to_vec()
-        } else {
-            messages
+        let (message_area, thread_area) = match self.thread {
+            Some(_) => {
+                let [main, side] =
+                    Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                        .areas(message_area);
+                (main, Some(side))
+            }
+            None => (message_area, None),
         };
 
-        Paragraph::new(visible_messages.join("\n"))
+        let visible_messages = visible_rows(
+            self.messages
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| !m.is_reply()),
+            message_area.height.saturating_sub(2),
+            true,
+            self.read_markers,
+        );
+
+        Paragraph::new(Text::from(visible_messages))
             .block(Block::default().borders(Borders::ALL).title(self.room_id))
             .wrap(ratatui::widgets::Wrap { trim: false })
             .render(message_area, buf);
 
+        if let (Some(root), Some(thread_area)) = (self.thread, thread_area) {
+            let thread_messages = visible_rows(
+                self.messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, m)| m.in_thread(root)),
+                thread_area.height.saturating_sub(2),
+                false,
+                None,
+            );
+            Paragraph::new(Text::from(thread_messages))
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Thread (type to reply, /close to leave)"),
+                )
+                .wrap(ratatui::widgets::Wrap { trim: false })
+                .render(thread_area, buf);
+        }
+
+        Paragraph::new(typing_line(self.typing)).render(typing_area, buf);
+
         Paragraph::new(self.input)
-            .block(Block::default().borders(Borders::ALL).title("Input"))
+            .block(Block::default().borders(Borders::ALL).title(self.input_title))
             .render(input_area, buf);
     }
 }
@@ -1,24 +1,108 @@
-use serde::{Deserialize};
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
 use tokio_tungstenite::tungstenite::Bytes;
 
 use crate::Err;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct Response {
+    // message or direct
+    #[serde(rename = "type")]
+    pub event_type: Option<String>,
     pub sender: Option<String>,
-    pub content: Option<String>
+    pub content: Option<String>,
+    // chat, action or system. Missing on servers that predate message kinds
+    pub kind: Option<String>,
+    pub id: Option<String>,
+    pub room_id: Option<String>,
+    // Names picked out of @mentions by the server
+    pub mentions: Option<Vec<String>>,
+    // Position of the message in the room, also carried by read receipts and acks
+    pub seq: Option<i64>,
+    // Id this client gave a message it sent, set on the message and on its ack
+    pub client_id: Option<String>,
+    // Previous versions of the message, only counted to mark it as edited
+    pub edits: Option<Vec<serde_json::Value>>,
+    pub deleted: Option<bool>,
+    // Sent by a service account or a server bot
+    pub bot: Option<bool>,
+    // Emoji to the accounts that reacted with it
+    pub reactions: Option<BTreeMap<String, Vec<String>>>,
+    // Id of the message starting the thread this is a reply in
+    pub reply_to: Option<String>,
+    // Set on messages starting a thread and on thread_updated events
+    pub reply_count: Option<u32>,
+    // Only set on reaction events
+    pub message_id: Option<String>,
+    pub emoji: Option<String>,
+    pub account: Option<String>,
+    pub added: Option<bool>,
+    // Only set on typing events
+    pub user: Option<String>,
+    pub active: Option<bool>,
+    // Only set on room_mode events
+    pub can_post: Option<bool>,
+    pub slow_mode_secs: Option<u32>,
+    // Only set on rejected events
+    pub reason: Option<String>,
+    // Only set on direct messages
+    pub recipient: Option<String>,
+    pub delivered: Option<bool>,
 }
 impl Response {
     pub fn new(json_bytes: Bytes) -> Response {
         let json_string = json_bytes.trim_ascii();
-        match serde_json::from_slice::<Response>(json_string) {
-            Ok(res) => {
-                res
-            }, 
-            Err(e) => {
-                // println!("Unable to Read message");
-                Response { sender: None, content: None }
-            }
+        // Anything unreadable comes back empty and gets skipped by the room
+        serde_json::from_slice::<Response>(json_string).unwrap_or_default()
+    }
+
+    // The reason the server gave for closing the connection, shown like a system message
+    pub fn closed(reason: String) -> Response {
+        Response {
+            event_type: Some("closed".to_string()),
+            kind: Some("system".to_string()),
+            content: Some(format!("Disconnected: {reason}")),
+            ..Response::default()
+        }
+    }
+
+    /*
+     * Typed events are always worth passing on, untyped ones only when they look like a message
+     */
+    pub fn is_readable(&self) -> bool {
+        self.event_type.is_some() || (self.sender.is_some() && self.content.is_some())
+    }
+
+    pub fn display_line(&self) -> String {
+        let sender = self.sender.as_deref().unwrap_or_default();
+        let message = self.content.as_deref().unwrap_or_default();
+        if self.event_type.as_deref() == Some("direct") {
+            let recipient = self.recipient.as_deref().unwrap_or_default();
+            return match self.delivered {
+                Some(false) => format!(
+                    "[DM] {sender} -> {recipient}:{message} (delivered when they connect)"
+                ),
+                _ => format!("[DM] {sender} -> {recipient}:{message}"),
+            };
+        }
+        if self.deleted == Some(true) {
+            return format!("{sender}: [message deleted]");
+        }
+
+        let edited = if self.edits.as_ref().is_some_and(|edits| !edits.is_empty()) {
+            " (edited)"
+        } else {
+            ""
+        };
+        let sender = match self.bot {
+            Some(true) => format!("[bot] {sender}"),
+            _ => sender.to_string(),
+        };
+        match self.kind.as_deref() {
+            Some("action") => format!("* {sender} {message}{edited}"),
+            Some("system") => format!("-- {message}"),
+            _ => format!("{sender}:{message}{edited}"),
         }
     }
 }
\ No newline at end of file
@@ -5,10 +5,42 @@ use tokio::sync::{
     Mutex,
     mpsc::{Receiver, Sender},
 };
-use tokio_tungstenite::connect_async;
+use tokio_tungstenite::{Connector, connect_async_tls_with_config};
 
 use crate::{Err, response::Response};
 
+/*
+ * Full websocket url for a path on the server. BASE_URL can carry its own scheme, wss:// or
+ * https:// for a server behind TLS, and anything without one is plain ws://
+ */
+pub fn websocket_url(base_url: &str, path: &str) -> String {
+    let base_url = base_url.trim_end_matches('/');
+    if base_url.starts_with("ws://") || base_url.starts_with("wss://") {
+        return format!("{base_url}{path}");
+    }
+    if let Some(host) = base_url.strip_prefix("https://") {
+        return format!("wss://{host}{path}");
+    }
+    let host = base_url.strip_prefix("http://").unwrap_or(base_url);
+    format!("ws://{host}{path}")
+}
+
+/*
+ * Trusts the PEM certificate in TLS_CA_FILE on top of the system ones, for servers using a
+ * self signed certificate. The system defaults are used when it is not set.
+ */
+fn tls_connector() -> Result<Option<Connector>, Err> {
+    let Ok(ca_file) = std::env::var("TLS_CA_FILE") else {
+        return Ok(None);
+    };
+    let pem = std::fs::read(&ca_file)?;
+    let ca = native_tls::Certificate::from_pem(&pem)?;
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(ca)
+        .build()?;
+    Ok(Some(Connector::NativeTls(connector)))
+}
+
 pub async fn start_listening(
     url: String,
     ending_rx: tokio::sync::watch::Receiver<bool>,
@@ -25,7 +57,8 @@ pub async fn start_listening(
     let file_to_write = Arc::new(Mutex::new(file_to_write));
     let writer_file_mutex = Arc::clone(&file_to_write);
 
-    let connection = connect_async(&url).await;
+    let connector = tls_connector()?;
+    let connection = connect_async_tls_with_config(&url, None, false, connector).await;
 
     if connection.is_err() {
         println!("Cannot connect to server {:?}", connection.err());
@@ -85,8 +118,16 @@ pub async fn start_listening(
     read.for_each(|message| async {
         match message {
             Ok(data) => {
-                let res = Response::new(data.into_data());
-                if res.content.is_none() || res.sender.is_none() {
+                let res = match data {
+                    // Shown in the room so a refused join says why
+                    tokio_tungstenite::tungstenite::Message::Close(Some(frame))
+                        if !frame.reason.is_empty() =>
+                    {
+                        Response::closed(frame.reason.to_string())
+                    }
+                    data => Response::new(data.into_data()),
+                };
+                if !res.is_readable() {
                     return;
                 }
 
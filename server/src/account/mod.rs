@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use mongodb::{Collection, Database, bson::doc};
+use serde::{Deserialize, Serialize};
+
+use crate::Err;
+
+pub const ACCOUNT_COLLECTION: &str = "accounts";
+
+// Everything the server keeps about an account between sessions. Documents are created the
+// first time there is something to keep.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Account {
+    pub account: String,
+    // Room id to the sequence number of the last message read in it
+    #[serde(default)]
+    pub read_markers: HashMap<String, i64>,
+}
+
+impl Account {
+    pub fn read_marker(&self, room_id: &str) -> i64 {
+        self.read_markers.get(room_id).copied().unwrap_or_default()
+    }
+}
+
+pub async fn find(database: &Database, account: &str) -> Result<Account, Err> {
+    let collection: Collection<Account> = database.collection(ACCOUNT_COLLECTION);
+    let found = collection.find_one(doc! {"account": account}).await?;
+    Ok(found.unwrap_or_else(|| Account {
+        account: account.to_string(),
+        ..Account::default()
+    }))
+}
+
+/*
+ * Moves the read marker forward, never back, so receipts arriving out of order are harmless
+ */
+pub async fn mark_read(
+    database: &Database,
+    account: &str,
+    room_id: &str,
+    seq: i64,
+) -> Result<(), Err> {
+    let collection: Collection<Account> = database.collection(ACCOUNT_COLLECTION);
+    collection
+        .update_one(
+            doc! {"account": account},
+            doc! {"$max": {format!("read_markers.{room_id}"): seq}},
+        )
+        .upsert(true)
+        .await?;
+    Ok(())
+}
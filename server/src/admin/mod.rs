@@ -0,0 +1,449 @@
+use std::sync::Arc;
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    web::{self, Query},
+};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database,
+    bson::{Uuid, doc},
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    RoomMap, UserMap,
+    dto::{
+        AdminActionDTO, AdminMemberDTO, AdminRoomDTO, AnnouncementDTO, CreateServiceAccountDTO,
+        ModerationLogQueryDTO, ReasonQueryDTO, ServiceAccountDTO,
+    },
+    filter::{MODERATION_LOG_COLLECTION, ModerationLogEntry},
+    roomwebserver::controller::is_valid_room_id,
+    roomwebserver::server::Room,
+    serviceaccount::{self, ServiceAccount},
+    settings,
+    webhook::{DEAD_LETTER_COLLECTION, DeadLetter},
+};
+
+pub const DEFAULT_CLOSE_REASON: &str = "Disconnected by an admin";
+pub const MAX_DEAD_LETTERS: usize = 100;
+pub const MAX_MODERATION_LOG_ENTRIES: usize = 100;
+
+// Token from the ADMIN_TOKEN variable. Every admin endpoint is refused when it is not set.
+#[derive(Debug)]
+pub struct AdminAuth {
+    token: Option<String>,
+}
+
+impl AdminAuth {
+    pub fn from_env() -> AdminAuth {
+        let token = std::env::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.trim().is_empty());
+        if token.is_none() {
+            println!("ADMIN_TOKEN not set, the admin endpoints are disabled");
+        }
+        AdminAuth { token }
+    }
+
+    /*
+     * Expects the token as "Authorization: Bearer <token>"
+     */
+    pub fn check(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(token) = &self.token else {
+            return Err(HttpResponse::Forbidden().body("Admin endpoints are disabled"));
+        };
+        let presented = req
+            .headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match presented {
+            Some(presented) if same_token(presented, token) => Ok(()),
+            _ => Err(HttpResponse::Unauthorized().body("Missing or wrong admin token")),
+        }
+    }
+}
+
+// Looks at every byte so the time taken does not give away how much of the token matched
+pub fn same_token(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn open_rooms(rooms: &RoomMap) -> Vec<Arc<Mutex<Room>>> {
+    rooms.lock().await.values().cloned().collect()
+}
+
+async fn open_room(rooms: &RoomMap, room_id: &str) -> Result<Arc<Mutex<Room>>, HttpResponse> {
+    rooms
+        .lock()
+        .await
+        .get(room_id)
+        .cloned()
+        .ok_or_else(|| HttpResponse::NotFound().body("Room is not open"))
+}
+
+pub async fn list_rooms(
+    req: HttpRequest,
+    admin: web::Data<AdminAuth>,
+    rooms: web::Data<RoomMap>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let mut entries = Vec::new();
+    for room in open_rooms(&rooms).await {
+        let room = room.lock().await;
+        entries.push(AdminRoomDTO {
+            room_id: room.room_id().to_string(),
+            members: room
+                .members()
+                .map(|(user_id, member)| AdminMemberDTO {
+                    user_id: user_id.to_string(),
+                    account: member.account.to_string(),
+                    username: member.username.to_string(),
+                })
+                .collect(),
+            topic: room.topic().map(str::to_string),
+            latest_seq: room.latest_seq(),
+            unsaved: room.unsaved_count(),
+            legal_hold: room.settings().legal_hold,
+        });
+    }
+    entries.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+    HttpResponse::Ok().json(entries)
+}
+
+/*
+ * Disconnects everyone in the room, which stores its messages and closes it
+ */
+pub async fn close_room(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: Query<ReasonQueryDTO>,
+    admin: web::Data<AdminAuth>,
+    rooms: web::Data<RoomMap>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let room = match open_room(&rooms, &path).await {
+        Ok(room) => room,
+        Err(res) => return res,
+    };
+    let reason = query.reason.as_deref().unwrap_or(DEFAULT_CLOSE_REASON);
+    let affected = room.lock().await.close(reason).await;
+    HttpResponse::Ok().json(AdminActionDTO { affected })
+}
+
+pub async fn flush_room(
+    req: HttpRequest,
+    path: web::Path<String>,
+    admin: web::Data<AdminAuth>,
+    rooms: web::Data<RoomMap>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let room = match open_room(&rooms, &path).await {
+        Ok(room) => room,
+        Err(res) => return res,
+    };
+    let flushed = room.lock().await.flush().await;
+    match flushed {
+        Ok(affected) => HttpResponse::Ok().json(AdminActionDTO { affected }),
+        Err(e) => {
+            println!("Unable to flush room {e:?}");
+            HttpResponse::InternalServerError().body("Unable to store the room messages")
+        }
+    }
+}
+
+/*
+ * Puts a room on a legal hold, or takes it off with DELETE. Nothing in a room on hold is
+ * removed by retention, whether it is open or not.
+ */
+pub async fn legal_hold(
+    req: HttpRequest,
+    path: web::Path<String>,
+    admin: web::Data<AdminAuth>,
+    rooms: web::Data<RoomMap>,
+    database: web::Data<Database>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let room_id = path.into_inner();
+    if !is_valid_room_id(&room_id) {
+        return HttpResponse::BadRequest().body("Invalid room id");
+    }
+    let hold = req.method() != actix_web::http::Method::DELETE;
+
+    // Held until the stored settings are written so the room cannot open in between
+    let guard_rooms = rooms.lock().await;
+    if let Some(room) = guard_rooms.get(&room_id) {
+        room.lock().await.set_legal_hold(hold);
+        return HttpResponse::NoContent().finish();
+    }
+    let mut room_settings = match settings::find(&database, &room_id).await {
+        Ok(room_settings) => room_settings,
+        Err(e) => {
+            println!("Unable to read room settings {e:?}");
+            return HttpResponse::InternalServerError().body("Unable to read room settings");
+        }
+    };
+    room_settings.legal_hold = hold;
+    let saved = settings::save(&database, &room_settings).await;
+    drop(guard_rooms);
+    match saved {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            println!("Unable to store room settings {e:?}");
+            HttpResponse::InternalServerError().body("Unable to store room settings")
+        }
+    }
+}
+
+/*
+ * Disconnects every session of the account, in whichever rooms they are in
+ */
+pub async fn disconnect_account(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: Query<ReasonQueryDTO>,
+    admin: web::Data<AdminAuth>,
+    rooms: web::Data<RoomMap>,
+    users: web::Data<UserMap>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let account = path.into_inner();
+
+    // Collected first so the user map is not held while the rooms are locked
+    let mut sessions: Vec<(Uuid, String)> = Vec::new();
+    for room_users in users.lock().await.values() {
+        for user in room_users {
+            let user = user.lock().await;
+            if !user.disconnected && *user.account == account {
+                sessions.push((user.user_id, user.room_id.to_string()));
+            }
+        }
+    }
+
+    let reason = query.reason.as_deref().unwrap_or(DEFAULT_CLOSE_REASON);
+    let mut affected = 0;
+    for (user_id, room_id) in sessions {
+        let Ok(room) = open_room(&rooms, &room_id).await else {
+            continue;
+        };
+        match room.lock().await.kick(user_id, reason).await {
+            Ok(()) => affected += 1,
+            Err(e) => println!("Unable to disconnect {account} from {room_id} {e:?}"),
+        }
+    }
+    if affected == 0 {
+        return HttpResponse::NotFound().body("Account is not connected");
+    }
+    HttpResponse::Ok().json(AdminActionDTO { affected })
+}
+
+/*
+ * Webhook deliveries that failed every try, newest first
+ */
+pub async fn dead_letters(
+    req: HttpRequest,
+    admin: web::Data<AdminAuth>,
+    database: web::Data<Database>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let collection: Collection<DeadLetter> = database.collection(DEAD_LETTER_COLLECTION);
+    let found: Result<Vec<DeadLetter>, mongodb::error::Error> = match collection
+        .find(doc! {})
+        .sort(doc! {"failed_at": -1})
+        .limit(MAX_DEAD_LETTERS as i64)
+        .await
+    {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match found {
+        Ok(dead_letters) => HttpResponse::Ok().json(dead_letters),
+        Err(e) => {
+            println!("Unable to read dead letters {e:?}");
+            HttpResponse::InternalServerError().body("Unable to read dead letters")
+        }
+    }
+}
+
+/*
+ * What the content filters did about messages, newest first
+ */
+pub async fn moderation_log(
+    req: HttpRequest,
+    query: Query<ModerationLogQueryDTO>,
+    admin: web::Data<AdminAuth>,
+    database: web::Data<Database>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let filter = match &query.room_id {
+        Some(room_id) => doc! {"room_id": room_id},
+        None => doc! {},
+    };
+    let collection: Collection<ModerationLogEntry> = database.collection(MODERATION_LOG_COLLECTION);
+    let found: Result<Vec<ModerationLogEntry>, mongodb::error::Error> = match collection
+        .find(filter)
+        .sort(doc! {"logged_at": -1})
+        .limit(MAX_MODERATION_LOG_ENTRIES as i64)
+        .await
+    {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match found {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            println!("Unable to read the moderation log {e:?}");
+            HttpResponse::InternalServerError().body("Unable to read the moderation log")
+        }
+    }
+}
+
+/*
+ * Sends the announcement into every open room as a system message
+ */
+pub async fn announce(
+    req: HttpRequest,
+    details: web::Json<AnnouncementDTO>,
+    admin: web::Data<AdminAuth>,
+    rooms: web::Data<RoomMap>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let content = details.content.trim();
+    if content.is_empty() {
+        return HttpResponse::BadRequest().body("Announcements need some content");
+    }
+    let rooms = open_rooms(&rooms).await;
+    for room in rooms.iter() {
+        room.lock().await.announce(content).await;
+    }
+    HttpResponse::Ok().json(AdminActionDTO {
+        affected: rooms.len(),
+    })
+}
+
+fn service_account_dto(
+    service_account: ServiceAccount,
+    token: Option<String>,
+) -> ServiceAccountDTO {
+    ServiceAccountDTO {
+        account: service_account.account,
+        read: service_account.read,
+        post: service_account.post,
+        created_at: service_account.created_at,
+        token,
+    }
+}
+
+/*
+ * Creates a service account and answers with its token, which is not shown again
+ */
+pub async fn create_service_account(
+    req: HttpRequest,
+    details: web::Json<CreateServiceAccountDTO>,
+    admin: web::Data<AdminAuth>,
+    database: web::Data<Database>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let details = details.into_inner();
+    // Names follow the same rules as room ids so they are safe to store anywhere
+    if !is_valid_room_id(&details.account) {
+        return HttpResponse::BadRequest().body(
+            "Account names can only contain letters, numbers, '-' and '_' and be at most 64 characters",
+        );
+    }
+    if !serviceaccount::valid_scope(&details.read) || !serviceaccount::valid_scope(&details.post) {
+        return HttpResponse::BadRequest().body("Scopes have to be room ids or \"*\"");
+    }
+    match serviceaccount::exists(&database, &details.account).await {
+        Ok(false) => {}
+        Ok(true) => return HttpResponse::Conflict().body("Service account already exists"),
+        Err(e) => {
+            println!("Unable to look up service accounts {e:?}");
+            return HttpResponse::InternalServerError().body("Unable to create service account");
+        }
+    }
+
+    let (service_account, token) = ServiceAccount::new(details.account, details.read, details.post);
+    match serviceaccount::create(&database, &service_account).await {
+        Ok(()) => {
+            println!("Created service account {}", service_account.account);
+            HttpResponse::Created().json(service_account_dto(service_account, Some(token)))
+        }
+        Err(e) => {
+            println!("Unable to store service account {e:?}");
+            HttpResponse::InternalServerError().body("Unable to create service account")
+        }
+    }
+}
+
+pub async fn list_service_accounts(
+    req: HttpRequest,
+    admin: web::Data<AdminAuth>,
+    database: web::Data<Database>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    match serviceaccount::list(&database).await {
+        Ok(service_accounts) => HttpResponse::Ok().json(
+            service_accounts
+                .into_iter()
+                .map(|service_account| service_account_dto(service_account, None))
+                .collect::<Vec<ServiceAccountDTO>>(),
+        ),
+        Err(e) => {
+            println!("Unable to read service accounts {e:?}");
+            HttpResponse::InternalServerError().body("Unable to read service accounts")
+        }
+    }
+}
+
+/*
+ * Deletes the service account, its token stops working right away
+ */
+pub async fn revoke_service_account(
+    req: HttpRequest,
+    path: web::Path<String>,
+    admin: web::Data<AdminAuth>,
+    database: web::Data<Database>,
+) -> HttpResponse {
+    if let Err(res) = admin.check(&req) {
+        return res;
+    }
+    let account = path.into_inner();
+    match serviceaccount::revoke(&database, &account).await {
+        Ok(true) => {
+            println!("Revoked service account {account}");
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().body("No such service account"),
+        Err(e) => {
+            println!("Unable to revoke service account {e:?}");
+            HttpResponse::InternalServerError().body("Unable to revoke service account")
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use actix_web::{HttpRequest, HttpResponse};
+use mongodb::Database;
+
+use crate::{
+    admin::same_token,
+    serviceaccount::{self, ANY_ROOM, ServiceAccount},
+};
+
+// Whoever an API request came from
+#[derive(Debug)]
+pub struct ApiCaller {
+    pub account: String,
+    // Unset for tokens from API_TOKENS, which reach every room
+    service_account: Option<ServiceAccount>,
+}
+
+impl ApiCaller {
+    // Messages from service accounts are marked as sent by a bot
+    pub fn is_bot(&self) -> bool {
+        self.service_account.is_some()
+    }
+
+    pub fn can_read(&self, room_id: &str) -> bool {
+        self.service_account
+            .as_ref()
+            .is_none_or(|service_account| service_account.can_read(room_id))
+    }
+
+    // Rooms the caller is held to reading, none when it can read every room
+    pub fn read_scope(&self) -> Option<&[String]> {
+        self.service_account
+            .as_ref()
+            .map(|service_account| service_account.read.as_slice())
+            .filter(|read| !read.iter().any(|room| room == ANY_ROOM))
+    }
+
+    pub fn can_post(&self, room_id: &str) -> bool {
+        self.service_account
+            .as_ref()
+            .is_none_or(|service_account| service_account.can_post(room_id))
+    }
+}
+
+// Tokens from the API_TOKENS variable, written as account=token pairs separated by commas.
+// Messages posted with a token are sent as its account. Service account tokens are checked
+// after these.
+#[derive(Debug)]
+pub struct ApiTokens {
+    accounts: HashMap<String, String>,
+}
+
+impl ApiTokens {
+    pub fn from_env() -> ApiTokens {
+        let mut accounts = HashMap::new();
+        for pair in std::env::var("API_TOKENS").unwrap_or_default().split(',') {
+            let pair = pair.trim();
+            if pair.is_empty() {
+                continue;
+            }
+            match pair.split_once('=') {
+                Some((account, token))
+                    if !account.trim().is_empty() && !token.trim().is_empty() =>
+                {
+                    accounts.insert(account.trim().to_string(), token.trim().to_string());
+                }
+                _ => println!("Ignoring API token without an account=token form"),
+            }
+        }
+        if accounts.is_empty() {
+            println!("API_TOKENS not set, only service accounts can use the API");
+        }
+        ApiTokens { accounts }
+    }
+
+    /*
+     * The caller whose token came as "Authorization: Bearer <token>"
+     */
+    pub async fn check(
+        &self,
+        req: &HttpRequest,
+        database: &Database,
+    ) -> Result<ApiCaller, HttpResponse> {
+        let presented = req
+            .headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+        let Some(presented) = presented else {
+            return Err(HttpResponse::Unauthorized().body("Missing API token"));
+        };
+        self.caller(presented, database).await
+    }
+
+    /*
+     * The caller the token belongs to, for tokens that came some other way than the header
+     */
+    pub async fn caller(
+        &self,
+        presented: &str,
+        database: &Database,
+    ) -> Result<ApiCaller, HttpResponse> {
+        // Every token is compared so the time taken does not tell which ones exist
+        let mut found = None;
+        for (account, token) in self.accounts.iter() {
+            if same_token(presented, token) {
+                found = Some(account.clone());
+            }
+        }
+        if let Some(account) = found {
+            return Ok(ApiCaller {
+                account,
+                service_account: None,
+            });
+        }
+
+        match serviceaccount::find_by_token(database, presented).await {
+            Ok(Some(service_account)) => Ok(ApiCaller {
+                account: service_account.account.clone(),
+                service_account: Some(service_account),
+            }),
+            Ok(None) => Err(HttpResponse::Unauthorized().body("Wrong API token")),
+            Err(e) => {
+                println!("Unable to look up service account token {e:?}");
+                Err(HttpResponse::ServiceUnavailable().body("Unable to check the API token"))
+            }
+        }
+    }
+
+    /*
+     * The account a websocket signs in as when it came with a token. Service accounts only
+     * use the API, so their tokens are turned away here.
+     */
+    pub async fn sign_in(
+        &self,
+        token: Option<&str>,
+        database: &Database,
+    ) -> Result<Option<String>, HttpResponse> {
+        let Some(token) = token else {
+            return Ok(None);
+        };
+        let caller = self.caller(token, database).await?;
+        if caller.is_bot() {
+            return Err(HttpResponse::Forbidden().body("Service accounts can only use the API"));
+        }
+        Ok(Some(caller.account))
+    }
+}
@@ -0,0 +1,147 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    bot::{Bot, BotAction, BotCommand},
+    message::{Message, MessageKind},
+};
+
+pub const MAX_REMINDER_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub fn by_name(name: &str) -> Option<Arc<dyn Bot>> {
+    match name {
+        "echo" => Some(Arc::new(EchoBot)),
+        "remind" => Some(Arc::new(RemindBot)),
+        "welcome" => Some(Arc::new(WelcomeBot)),
+        _ => None,
+    }
+}
+
+// Says back whatever follows !echo
+#[derive(Debug)]
+pub struct EchoBot;
+
+impl Bot for EchoBot {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn on_message(&self, _room_id: &str, msg: &Message) -> Vec<BotAction> {
+        if msg.kind() != MessageKind::Chat {
+            return Vec::new();
+        }
+        match msg.content().strip_prefix("!echo ") {
+            Some(text) if !text.trim().is_empty() => vec![BotAction::Say(text.trim().to_string())],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Mentions whoever asked once their reminder is due
+#[derive(Debug)]
+pub struct RemindBot;
+
+const REMIND_COMMANDS: &[BotCommand] = &[BotCommand {
+    name: "remind",
+    usage: "/remind <30s|10m|2h> <text>",
+    description: "Get mentioned with the text once the time is up",
+}];
+
+/*
+ * A number followed by s, m or h
+ */
+fn parse_delay(delay: &str) -> Option<Duration> {
+    let unit = delay.chars().last()?;
+    let amount: u64 = delay[..delay.len() - unit.len_utf8()].parse().ok()?;
+    let seconds = match unit {
+        's' => amount,
+        'm' => amount.checked_mul(60)?,
+        'h' => amount.checked_mul(60 * 60)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+impl Bot for RemindBot {
+    fn name(&self) -> &'static str {
+        "remind"
+    }
+
+    fn commands(&self) -> &'static [BotCommand] {
+        REMIND_COMMANDS
+    }
+
+    fn on_command(&self, _room_id: &str, account: &str, _name: &str, args: &str) -> Vec<BotAction> {
+        let usage = REMIND_COMMANDS[0].usage;
+        let Some((delay, text)) = args.split_once(char::is_whitespace) else {
+            return vec![BotAction::Whisper(format!("Usage: {usage}"))];
+        };
+        let delay = match parse_delay(delay) {
+            Some(delay) if !delay.is_zero() && delay <= MAX_REMINDER_DELAY => delay,
+            _ => {
+                return vec![BotAction::Whisper(format!(
+                    "Reminders need a time between 1s and 24h. Usage: {usage}"
+                ))];
+            }
+        };
+        vec![
+            BotAction::Whisper(format!("I will remind you in {}s", delay.as_secs())),
+            BotAction::Later {
+                delay,
+                action: Box::new(BotAction::Say(format!(
+                    "@{account} reminder: {}",
+                    text.trim()
+                ))),
+            },
+        ]
+    }
+}
+
+// Greets everyone joining, only they see it
+#[derive(Debug)]
+pub struct WelcomeBot;
+
+impl Bot for WelcomeBot {
+    fn name(&self) -> &'static str {
+        "welcome"
+    }
+
+    fn on_join(&self, room_id: &str, username: &str) -> Vec<BotAction> {
+        vec![BotAction::Whisper(format!(
+            "Welcome to {room_id}, {username}! Type /help to see what you can do here."
+        ))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_take_seconds_minutes_and_hours() {
+        assert_eq!(parse_delay("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_delay("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_delay("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_delay("2d"), None);
+        assert_eq!(parse_delay("m"), None);
+        assert_eq!(parse_delay("1é"), None);
+    }
+
+    #[test]
+    fn reminders_mention_the_account() {
+        let actions = RemindBot.on_command("lobby", "alice", "remind", "1m stretch");
+        let Some(BotAction::Later { delay, action }) = actions.last() else {
+            panic!("No reminder was set, got {actions:?}");
+        };
+        assert_eq!(*delay, Duration::from_secs(60));
+        let BotAction::Say(content) = action.as_ref() else {
+            panic!("Reminder is not said in the room, got {action:?}");
+        };
+        assert_eq!(content, "@alice reminder: stretch");
+    }
+
+    #[test]
+    fn reminders_past_a_day_are_refused() {
+        let actions = RemindBot.on_command("lobby", "alice", "remind", "25h stretch");
+        assert!(matches!(actions.as_slice(), [BotAction::Whisper(_)]));
+    }
+}
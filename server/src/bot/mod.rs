@@ -0,0 +1,122 @@
+use std::{collections::HashSet, fmt::Debug, sync::Arc, time::Duration};
+
+use crate::message::Message;
+
+mod builtin;
+
+// A command a bot answers to in the rooms it follows, listed by /help with the room commands
+#[derive(Debug)]
+pub struct BotCommand {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+}
+
+// What a bot wants the room to do. Bots stay synchronous like commands, the room does the
+// sending. Nothing a bot says is passed on to bots again, so bots cannot set each other off.
+#[derive(Debug)]
+pub enum BotAction {
+    // Chat message sent under the bot's name and stored with the room
+    Say(String),
+    // System message only shown to the account that set the bot off, never stored
+    Whisper(String),
+    // Done once the delay is up, as long as the room is still around. Lost on a restart.
+    Later {
+        delay: Duration,
+        action: Box<BotAction>,
+    },
+}
+
+/*
+ * Something that follows rooms and reacts to what happens in them. Every hook does nothing
+ * unless the bot overrides it.
+ */
+pub trait Bot: Send + Sync + Debug {
+    // Also the name its messages are sent under
+    fn name(&self) -> &'static str;
+
+    fn commands(&self) -> &'static [BotCommand] {
+        &[]
+    }
+
+    // Every message members send into the room
+    fn on_message(&self, _room_id: &str, _msg: &Message) -> Vec<BotAction> {
+        Vec::new()
+    }
+
+    fn on_join(&self, _room_id: &str, _username: &str) -> Vec<BotAction> {
+        Vec::new()
+    }
+
+    // Only called with the names listed by commands. Mentions reach people by the account, not
+    // by the name they show up under.
+    fn on_command(
+        &self,
+        _room_id: &str,
+        _account: &str,
+        _name: &str,
+        _args: &str,
+    ) -> Vec<BotAction> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug)]
+enum Subscription {
+    Everywhere,
+    Rooms(HashSet<String>),
+}
+
+#[derive(Debug, Default)]
+pub struct BotRegistry {
+    bots: Vec<(Arc<dyn Bot>, Subscription)>,
+}
+
+impl BotRegistry {
+    /*
+     * Bots from the BOTS variable, comma separated. Each is a bot name optionally followed by
+     * the rooms it follows, like "welcome:lobby|general". Without rooms it follows all of them.
+     */
+    pub fn from_env() -> BotRegistry {
+        let mut registry = BotRegistry::default();
+        for entry in std::env::var("BOTS").unwrap_or_default().split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let (name, rooms) = entry.split_once(':').unwrap_or((entry, "*"));
+            let Some(bot) = builtin::by_name(name.trim()) else {
+                println!("No bot called {name}, skipping it");
+                continue;
+            };
+            let subscription = match rooms.trim() {
+                "*" => Subscription::Everywhere,
+                rooms => Subscription::Rooms(
+                    rooms
+                        .split('|')
+                        .map(|room| room.trim().to_string())
+                        .filter(|room| !room.is_empty())
+                        .collect(),
+                ),
+            };
+            println!("Running bot {} in {subscription:?}", bot.name());
+            registry.register(bot, subscription);
+        }
+        registry
+    }
+
+    fn register(&mut self, bot: Arc<dyn Bot>, subscription: Subscription) {
+        self.bots.push((bot, subscription));
+    }
+
+    pub fn for_room(&self, room_id: &str) -> Vec<Arc<dyn Bot>> {
+        self.bots
+            .iter()
+            .filter(|(_, subscription)| match subscription {
+                Subscription::Everywhere => true,
+                Subscription::Rooms(rooms) => rooms.contains(room_id),
+            })
+            .map(|(bot, _)| Arc::clone(bot))
+            .collect()
+    }
+}
@@ -0,0 +1,1275 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use mongodb::bson::Uuid;
+
+use crate::{
+    event::ServerEvent,
+    filter::{AutoModRule, FilterAction, MAX_ROOM_RULES, RuleMatcher},
+    message::{Message, MessageKind},
+    roomwebserver::server::Room,
+    settings::{DEFAULT_INVITE_EXPIRY, DuplicateNames, Invite},
+    webhook::{MAX_WEBHOOKS_PER_ROOM, Webhook},
+};
+
+pub const MAX_NICK_LENGTH: usize = 32;
+pub const MAX_EMOJI_LENGTH: usize = 16;
+pub const MAX_SLOW_MODE_SECS: u32 = 6 * 60 * 60;
+
+// What a command wants the room to do once it has finished running. Commands stay synchronous
+// so that they can work directly on the room, and the room takes care of the async sending.
+#[derive(Debug)]
+pub enum CommandEffect {
+    // Only sent back to the user that ran the command, never stored
+    Reply(String),
+    // Sent to every member and stored along with the rest of the room messages
+    Broadcast(Message),
+    // Change the name the user sends their messages under
+    Rename(Arc<String>),
+    // Hand a direct message over to the router, addressed by account name
+    Direct { recipient: String, content: String },
+    // Sent to every member without being stored as a message of its own
+    Notify(ServerEvent),
+    // Tell every member what they can post now that the room's posting rules changed
+    ModeChanged,
+}
+
+pub type CommandResult = Result<Vec<CommandEffect>, String>;
+pub type CommandHandler = fn(&mut Room, Uuid, &str) -> CommandResult;
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub handler: CommandHandler,
+}
+
+#[derive(Debug, Default)]
+pub struct CommandRegistry {
+    // BTreeMap so /help lists the commands in a stable order
+    commands: BTreeMap<&'static str, CommandSpec>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> CommandRegistry {
+        CommandRegistry::default()
+    }
+
+    /*
+     * Registry with all of the commands the server ships with. Adding a new command is just
+     * another register call here.
+     */
+    pub fn with_defaults() -> CommandRegistry {
+        let mut registry = CommandRegistry::new();
+        registry.register(CommandSpec {
+            name: "nick",
+            usage: "/nick <name>",
+            description: "Change your display name in this room",
+            handler: nick,
+        });
+        registry.register(CommandSpec {
+            name: "me",
+            usage: "/me <action>",
+            description: "Send an action message",
+            handler: me,
+        });
+        registry.register(CommandSpec {
+            name: "topic",
+            usage: "/topic [topic]",
+            description: "Show the room topic, or set it when one is given",
+            handler: topic,
+        });
+        registry.register(CommandSpec {
+            name: "who",
+            usage: "/who",
+            description: "List the members of this room",
+            handler: who,
+        });
+        registry.register(CommandSpec {
+            name: "msg",
+            usage: "/msg <user> <message>",
+            description: "Send a direct message to a user, delivered later if they are offline",
+            handler: msg,
+        });
+        registry.register(CommandSpec {
+            name: "edit",
+            usage: "/edit <message id> <text>",
+            description: "Edit one of your messages",
+            handler: edit,
+        });
+        registry.register(CommandSpec {
+            name: "delete",
+            usage: "/delete <message id>",
+            description: "Delete one of your messages",
+            handler: delete,
+        });
+        registry.register(CommandSpec {
+            name: "react",
+            usage: "/react <message id> <emoji>",
+            description: "React to a message, or take your reaction back",
+            handler: react,
+        });
+        registry.register(CommandSpec {
+            name: "reply",
+            usage: "/reply <message id> <text>",
+            description: "Reply in the thread of a message",
+            handler: reply,
+        });
+        registry.register(CommandSpec {
+            name: "slowmode",
+            usage: "/slowmode <seconds|off>",
+            description: "Make members wait between messages",
+            handler: slowmode,
+        });
+        registry.register(CommandSpec {
+            name: "readonly",
+            usage: "/readonly <on|off>",
+            description: "Only let moderators post, for announcements",
+            handler: readonly,
+        });
+        registry.register(CommandSpec {
+            name: "limit",
+            usage: "/limit <members|off>",
+            description: "Cap how many people can be in this room",
+            handler: limit,
+        });
+        registry.register(CommandSpec {
+            name: "private",
+            usage: "/private <on|off>",
+            description: "Only let invited accounts join this room",
+            handler: private,
+        });
+        registry.register(CommandSpec {
+            name: "invite",
+            usage: "/invite <account>",
+            description: "Let an account join this room without the password",
+            handler: invite,
+        });
+        registry.register(CommandSpec {
+            name: "uninvite",
+            usage: "/uninvite <account>",
+            description: "Take an account off the invited list",
+            handler: uninvite,
+        });
+        registry.register(CommandSpec {
+            name: "password",
+            usage: "/password <password|off>",
+            description: "Require a password to join this room",
+            handler: password,
+        });
+        registry.register(CommandSpec {
+            name: "names",
+            usage: "/names <reject|suffix>",
+            description: "Refuse joins under a name already in use, or add a number to it",
+            handler: names,
+        });
+        registry.register(CommandSpec {
+            name: "invitelink",
+            usage: "/invitelink [hours] [uses]",
+            description: "Create an invite token that gets someone into this room",
+            handler: invite_link,
+        });
+        registry.register(CommandSpec {
+            name: "invites",
+            usage: "/invites",
+            description: "List the invite tokens for this room that can still be used",
+            handler: invites,
+        });
+        registry.register(CommandSpec {
+            name: "revoke",
+            usage: "/revoke <token>",
+            description: "Stop an invite token from being used",
+            handler: revoke,
+        });
+        registry.register(CommandSpec {
+            name: "retention",
+            usage: "/retention <days|off> <messages|off>",
+            description: "Remove stored messages past an age or beyond a count (moderators)",
+            handler: retention,
+        });
+        registry.register(CommandSpec {
+            name: "webhook",
+            usage: "/webhook <add|remove> <url> or /webhook list",
+            description: "Post what happens in this room to another service",
+            handler: webhook,
+        });
+        registry.register(CommandSpec {
+            name: "automod",
+            usage: "/automod <reject|mask|flag> <links|words <a,b>|pattern <regex>>, /automod remove <n> or /automod list",
+            description: "Act on messages matching a rule before they reach the room",
+            handler: automod,
+        });
+        registry.register(CommandSpec {
+            name: "settings",
+            usage: "/settings",
+            description: "Show who can join this room",
+            handler: settings,
+        });
+        registry.register(CommandSpec {
+            name: "help",
+            usage: "/help",
+            description: "List the available commands",
+            handler: help,
+        });
+        registry
+    }
+
+    pub fn register(&mut self, spec: CommandSpec) {
+        self.commands.insert(spec.name, spec);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CommandSpec> {
+        self.commands.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CommandSpec> {
+        self.commands.values()
+    }
+
+    pub fn unknown_command(&self, name: &str) -> String {
+        let suggestion = self
+            .commands
+            .keys()
+            .find(|command| command.starts_with(name) || name.starts_with(*command));
+        match suggestion {
+            Some(command) if !name.is_empty() => {
+                format!(
+                    "Unknown command /{name}. Did you mean /{command}? Type /help for a list of commands"
+                )
+            }
+            _ => format!("Unknown command /{name}. Type /help for a list of commands"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Input<'a> {
+    Text(&'a str),
+    Command { name: &'a str, args: &'a str },
+}
+
+/*
+ * Anything starting with a single slash is a command. A double slash escapes it so that
+ * "//shrug" is sent as the text "/shrug".
+ */
+pub fn parse(txt: &str) -> Input<'_> {
+    if txt.starts_with("//") {
+        return Input::Text(&txt[1..]);
+    }
+    match txt.strip_prefix('/') {
+        Some(command) => {
+            let (name, args) = command
+                .split_once(char::is_whitespace)
+                .unwrap_or((command, ""));
+            Input::Command {
+                name,
+                args: args.trim(),
+            }
+        }
+        None => Input::Text(txt),
+    }
+}
+
+fn nick(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let new_name = args.trim();
+    if new_name.is_empty() {
+        return Err("Usage: /nick <name>".to_string());
+    }
+    if new_name.chars().any(char::is_whitespace) {
+        return Err("Names cannot contain spaces".to_string());
+    }
+    if new_name.chars().count() > MAX_NICK_LENGTH {
+        return Err(format!("Names can be at most {MAX_NICK_LENGTH} characters"));
+    }
+
+    let new_name = Arc::new(new_name.to_string());
+    let old_name = room.rename_member(user_id, Arc::clone(&new_name))?;
+    Ok(vec![
+        CommandEffect::Rename(Arc::clone(&new_name)),
+        CommandEffect::Broadcast(Message::system(
+            format!("{old_name} is now known as {new_name}"),
+            room.room_id(),
+        )),
+    ])
+}
+
+fn me(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    if args.is_empty() {
+        return Err("Usage: /me <action>".to_string());
+    }
+    let sender = room
+        .display_name(user_id)
+        .ok_or_else(|| "You are not a member of this room".to_string())?;
+    let account = room.account(user_id).unwrap_or_default();
+    Ok(vec![CommandEffect::Broadcast(
+        Message::with_kind(
+            Uuid::new(),
+            sender,
+            args.to_string(),
+            room.room_id(),
+            MessageKind::Action,
+        )
+        .with_author(&account),
+    )])
+}
+
+fn topic(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    if args.is_empty() {
+        let reply = match room.topic() {
+            Some(topic) => format!("Topic: {topic}"),
+            None => "No topic has been set".to_string(),
+        };
+        return Ok(vec![CommandEffect::Reply(reply)]);
+    }
+
+    let sender = room
+        .display_name(user_id)
+        .ok_or_else(|| "You are not a member of this room".to_string())?;
+    let account = room.account(user_id).unwrap_or_default();
+    // Checked up front so a refused topic is not set either
+    room.check_posting(&account)?;
+    let (topic, note) = room.screen(&account, args, None)?;
+    let mut effects = vec![CommandEffect::Broadcast(Message::system(
+        format!("{sender} changed the topic to: {topic}"),
+        room.room_id(),
+    ))];
+    effects.extend(note.map(CommandEffect::Reply));
+    room.set_topic(Some(topic));
+    Ok(effects)
+}
+
+fn msg(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let Some((recipient, content)) = args.split_once(char::is_whitespace) else {
+        return Err("Usage: /msg <user> <message>".to_string());
+    };
+    let content = content.trim();
+    if content.is_empty() {
+        return Err("Usage: /msg <user> <message>".to_string());
+    }
+    let account = room
+        .account(user_id)
+        .ok_or_else(|| "You are not a member of this room".to_string())?;
+    if account.as_str() == recipient {
+        return Err("You cannot send a direct message to yourself".to_string());
+    }
+    // Sent from this room, so it goes through the same filters as what is said in it
+    let (content, note) = room.screen(&account, content, None)?;
+    let mut effects = vec![CommandEffect::Direct {
+        recipient: recipient.to_string(),
+        content,
+    }];
+    effects.extend(note.map(CommandEffect::Reply));
+    Ok(effects)
+}
+
+/*
+ * Finds the message the user wants to change and makes sure they are allowed to, handing back
+ * the message along with the account making the change
+ */
+fn editable_message(
+    room: &Room,
+    user_id: Uuid,
+    id: &str,
+) -> Result<(Arc<Message>, Arc<String>), String> {
+    let id = Uuid::parse_str(id).map_err(|_| format!("{id} is not a valid message id"))?;
+    let account = room
+        .account(user_id)
+        .ok_or_else(|| "You are not a member of this room".to_string())?;
+    let msg = room
+        .find_message(id)
+        .ok_or_else(|| "No message with that id in this room".to_string())?;
+    if msg.is_deleted() {
+        return Err("That message has been deleted".to_string());
+    }
+    if msg.author() != account.as_str() && !room.is_moderator_member(user_id) {
+        return Err("You can only change your own messages".to_string());
+    }
+    Ok((msg, account))
+}
+
+fn edit(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let Some((id, content)) = args.split_once(char::is_whitespace) else {
+        return Err("Usage: /edit <message id> <text>".to_string());
+    };
+    let content = content.trim();
+    if content.is_empty() {
+        return Err("Usage: /edit <message id> <text>".to_string());
+    }
+    let (msg, account) = editable_message(room, user_id, id)?;
+    let (content, note) = room.screen(&account, content, Some(msg.id()))?;
+    let edited = Arc::new(msg.edited(content, &account));
+    room.replace_message(Arc::clone(&edited));
+    let mut effects = vec![CommandEffect::Notify(ServerEvent::MessageEdited(edited))];
+    effects.extend(note.map(CommandEffect::Reply));
+    Ok(effects)
+}
+
+fn delete(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    if args.is_empty() {
+        return Err("Usage: /delete <message id>".to_string());
+    }
+    let (msg, account) = editable_message(room, user_id, args)?;
+    let tombstone = Arc::new(msg.tombstone(&account));
+    room.replace_message(Arc::clone(&tombstone));
+    Ok(vec![CommandEffect::Notify(ServerEvent::MessageDeleted(
+        tombstone,
+    ))])
+}
+
+fn react(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let Some((id, emoji)) = args.split_once(char::is_whitespace) else {
+        return Err("Usage: /react <message id> <emoji>".to_string());
+    };
+    let emoji = emoji.trim();
+    if emoji.is_empty() || emoji.chars().any(char::is_whitespace) {
+        return Err("Usage: /react <message id> <emoji>".to_string());
+    }
+    if emoji.chars().count() > MAX_EMOJI_LENGTH {
+        return Err(format!(
+            "Reactions can be at most {MAX_EMOJI_LENGTH} characters"
+        ));
+    }
+
+    let id = Uuid::parse_str(id).map_err(|_| format!("{id} is not a valid message id"))?;
+    let account = room
+        .account(user_id)
+        .ok_or_else(|| "You are not a member of this room".to_string())?;
+    let msg = room
+        .find_message(id)
+        .ok_or_else(|| "No message with that id in this room".to_string())?;
+    if msg.is_deleted() {
+        return Err("That message has been deleted".to_string());
+    }
+
+    let (reacted, added) = msg.toggle_reaction(emoji, &account);
+    room.replace_message(Arc::new(reacted));
+    Ok(vec![CommandEffect::Notify(ServerEvent::Reaction {
+        message_id: id,
+        emoji: emoji.to_string(),
+        account: account.to_string(),
+        added,
+    })])
+}
+
+fn reply(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let Some((id, content)) = args.split_once(char::is_whitespace) else {
+        return Err("Usage: /reply <message id> <text>".to_string());
+    };
+    let content = content.trim();
+    if content.is_empty() {
+        return Err("Usage: /reply <message id> <text>".to_string());
+    }
+
+    let id = Uuid::parse_str(id).map_err(|_| format!("{id} is not a valid message id"))?;
+    let sender = room
+        .display_name(user_id)
+        .ok_or_else(|| "You are not a member of this room".to_string())?;
+    let account = room.account(user_id).unwrap_or_default();
+    let parent = room
+        .find_message(id)
+        .ok_or_else(|| "No message with that id in this room".to_string())?;
+    let root = match parent.reply_to() {
+        Some(root) => room
+            .find_message(root)
+            .ok_or_else(|| "The start of that thread is no longer available".to_string())?,
+        None => parent,
+    };
+
+    // The room counts the reply towards the thread once it went out
+    Ok(vec![CommandEffect::Broadcast(
+        Message::new(Uuid::new(), sender, content.to_string(), room.room_id())
+            .with_author(&account)
+            .in_thread(root.id()),
+    )])
+}
+
+fn limit(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let max_members = match args {
+        "off" => None,
+        _ => match args.parse::<u32>() {
+            Ok(max) if max > 0 => Some(max),
+            _ => return Err("Usage: /limit <members|off>".to_string()),
+        },
+    };
+    room.change_settings(user_id, |settings| settings.max_members = max_members)?;
+    let reply = match max_members {
+        Some(max) => format!("Room now allows at most {max} members"),
+        None => "Room no longer has a member limit".to_string(),
+    };
+    Ok(vec![CommandEffect::Reply(reply)])
+}
+
+fn slowmode(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let slow_mode_secs = match args {
+        "off" => None,
+        _ => match args.parse::<u32>() {
+            Ok(secs) if secs > 0 && secs <= MAX_SLOW_MODE_SECS => Some(secs),
+            _ => {
+                return Err(format!(
+                    "Usage: /slowmode <seconds|off>, at most {MAX_SLOW_MODE_SECS} seconds"
+                ));
+            }
+        },
+    };
+    room.change_settings(user_id, |settings| settings.slow_mode_secs = slow_mode_secs)?;
+    let content = match slow_mode_secs {
+        Some(secs) => format!("Slow mode is on, members can post once every {secs}s"),
+        None => "Slow mode is off".to_string(),
+    };
+    Ok(vec![
+        CommandEffect::Broadcast(Message::system(content, room.room_id())),
+        CommandEffect::ModeChanged,
+    ])
+}
+
+fn readonly(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let read_only = match args {
+        "on" => true,
+        "off" => false,
+        _ => return Err("Usage: /readonly <on|off>".to_string()),
+    };
+    room.change_settings(user_id, |settings| settings.read_only = read_only)?;
+    let content = if read_only {
+        "Room is read only, only moderators can post"
+    } else {
+        "Everyone can post in this room again"
+    };
+    Ok(vec![
+        CommandEffect::Broadcast(Message::system(content.to_string(), room.room_id())),
+        CommandEffect::ModeChanged,
+    ])
+}
+
+fn private(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let invite_only = match args {
+        "on" => true,
+        "off" => false,
+        _ => return Err("Usage: /private <on|off>".to_string()),
+    };
+    room.change_settings(user_id, |settings| settings.invite_only = invite_only)?;
+    let reply = if invite_only {
+        "Only invited accounts can join now"
+    } else {
+        "Room is no longer invite only"
+    };
+    Ok(vec![CommandEffect::Reply(reply.to_string())])
+}
+
+fn invite(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    if args.is_empty() || args.chars().any(char::is_whitespace) {
+        return Err("Usage: /invite <account>".to_string());
+    }
+    room.change_settings(user_id, |settings| {
+        if !settings.invited.iter().any(|invited| invited == args) {
+            settings.invited.push(args.to_string());
+        }
+    })?;
+    Ok(vec![CommandEffect::Reply(format!(
+        "{args} can now join this room"
+    ))])
+}
+
+fn uninvite(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    if args.is_empty() {
+        return Err("Usage: /uninvite <account>".to_string());
+    }
+    if !room
+        .settings()
+        .invited
+        .iter()
+        .any(|invited| invited == args)
+    {
+        return Err(format!("{args} is not on the invited list"));
+    }
+    room.change_settings(user_id, |settings| {
+        settings.invited.retain(|invited| invited != args)
+    })?;
+    Ok(vec![CommandEffect::Reply(format!(
+        "{args} is no longer invited"
+    ))])
+}
+
+fn password(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    if args.is_empty() {
+        return Err("Usage: /password <password|off>".to_string());
+    }
+    let password = (args != "off").then_some(args);
+    room.change_settings(user_id, |settings| settings.set_password(password))??;
+    let reply = match password {
+        Some(_) => "Joining this room now needs the password",
+        None => "Room no longer needs a password",
+    };
+    Ok(vec![CommandEffect::Reply(reply.to_string())])
+}
+
+fn names(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let policy = match args {
+        "reject" => DuplicateNames::Reject,
+        "suffix" => DuplicateNames::Suffix,
+        _ => return Err("Usage: /names <reject|suffix>".to_string()),
+    };
+    room.change_settings(user_id, |settings| settings.duplicate_names = policy)?;
+    let reply = match policy {
+        DuplicateNames::Reject => "Joining under a name already in use is now refused",
+        DuplicateNames::Suffix => "Names already in use now get a number added",
+    };
+    Ok(vec![CommandEffect::Reply(reply.to_string())])
+}
+
+fn invite_link(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let usage = || "Usage: /invitelink [hours] [uses]".to_string();
+    let mut args = args.split_whitespace();
+    let expires_in = match args.next() {
+        Some(hours) => match hours.parse::<u64>() {
+            Ok(hours) if hours > 0 => Duration::from_secs(hours * 60 * 60),
+            _ => return Err(usage()),
+        },
+        None => DEFAULT_INVITE_EXPIRY,
+    };
+    let max_uses = match args.next() {
+        Some(uses) => match uses.parse::<u32>() {
+            Ok(uses) if uses > 0 => Some(uses),
+            _ => return Err(usage()),
+        },
+        None => None,
+    };
+
+    let account = room.settings_manager(user_id)?;
+    let invite = room.change_settings(user_id, |settings| {
+        settings.create_invite(&account, expires_in, max_uses)
+    })?;
+    Ok(vec![CommandEffect::Reply(format!(
+        "Invite {}. Join with /ws/joinroom?room_id={}&invite={}",
+        invite.describe(),
+        room.room_id(),
+        invite.token
+    ))])
+}
+
+fn invites(room: &mut Room, user_id: Uuid, _args: &str) -> CommandResult {
+    room.settings_manager(user_id)?;
+    let invites = room.settings().outstanding_invites();
+    if invites.is_empty() {
+        return Ok(vec![CommandEffect::Reply(
+            "No invites can be used right now".to_string(),
+        )]);
+    }
+    let lines: Vec<String> = invites.iter().map(Invite::describe).collect();
+    Ok(vec![CommandEffect::Reply(format!(
+        "Invites:\n{}",
+        lines.join("\n")
+    ))])
+}
+
+fn revoke(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    if args.is_empty() {
+        return Err("Usage: /revoke <token>".to_string());
+    }
+    if !room.change_settings(user_id, |settings| settings.revoke_invite(args))? {
+        return Err(format!("No invite {args} in this room"));
+    }
+    Ok(vec![CommandEffect::Reply(format!("Invite {args} revoked"))])
+}
+
+fn retention(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let usage = "Usage: /retention <days|off> <messages|off>";
+    let parse = |arg: Option<&str>| match arg {
+        Some("off") => Ok(None),
+        Some(arg) => match arg.parse::<u32>() {
+            Ok(value) if value > 0 => Ok(Some(value)),
+            _ => Err(usage.to_string()),
+        },
+        None => Err(usage.to_string()),
+    };
+    let mut args = args.split_whitespace();
+    let days = parse(args.next())?;
+    let messages = parse(args.next())?;
+    if args.next().is_some() {
+        return Err(usage.to_string());
+    }
+    // Removed history cannot be brought back, so this stays with moderators even if others
+    // get to manage rooms later
+    if !room.is_moderator_member(user_id) {
+        return Err("Only moderators can change how long messages are kept".to_string());
+    }
+    let legal_hold = room.change_settings(user_id, |settings| {
+        settings.retention_days = days;
+        settings.retention_messages = messages;
+        settings.legal_hold
+    })?;
+    let mut reply = match (days, messages) {
+        (None, None) => "Messages in this room are kept forever now".to_string(),
+        _ => "Older messages will be removed from this room within the hour".to_string(),
+    };
+    if legal_hold {
+        reply.push_str(", once the legal hold on it is lifted");
+    }
+    Ok(vec![CommandEffect::Reply(reply)])
+}
+
+fn webhook(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let usage = "Usage: /webhook <add|remove> <url> or /webhook list";
+    let (action, url) = args.split_once(' ').unwrap_or((args, ""));
+    let url = url.trim();
+    let account = room.settings_manager(user_id)?;
+    let reply = match (action, url) {
+        ("list", "") => {
+            let webhooks = &room.settings().webhooks;
+            if webhooks.is_empty() {
+                "This room has no webhooks".to_string()
+            } else {
+                webhooks
+                    .iter()
+                    .map(|webhook| format!("{} added by {}", webhook.url, webhook.created_by))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            }
+        }
+        ("add", url) if !url.is_empty() => {
+            room.webhooks().check_url(url)?;
+            let added = room.change_settings(user_id, |settings| {
+                if settings.webhooks.iter().any(|webhook| webhook.url == url) {
+                    return Err(format!("{url} is already a webhook of this room"));
+                }
+                if settings.webhooks.len() >= MAX_WEBHOOKS_PER_ROOM {
+                    return Err(format!(
+                        "Rooms can have at most {MAX_WEBHOOKS_PER_ROOM} webhooks"
+                    ));
+                }
+                let webhook = Webhook::new(url.to_string(), &account);
+                settings.webhooks.push(webhook.clone());
+                Ok(webhook)
+            })??;
+            // Only ever shown here, to whoever added it
+            format!(
+                "Added webhook {}. Payloads are signed with HMAC-SHA256 using the secret {}",
+                added.url, added.secret
+            )
+        }
+        ("remove", url) if !url.is_empty() => {
+            let removed = room.change_settings(user_id, |settings| {
+                let before = settings.webhooks.len();
+                settings.webhooks.retain(|webhook| webhook.url != url);
+                settings.webhooks.len() != before
+            })?;
+            match removed {
+                true => format!("Removed webhook {url}"),
+                false => format!("{url} is not a webhook of this room"),
+            }
+        }
+        _ => return Err(usage.to_string()),
+    };
+    Ok(vec![CommandEffect::Reply(reply)])
+}
+
+fn automod(room: &mut Room, user_id: Uuid, args: &str) -> CommandResult {
+    let usage = "Usage: /automod <reject|mask|flag> <links|words <a,b>|pattern <regex>>, /automod remove <n> or /automod list";
+    let args = args.trim();
+    let (action, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let rest = rest.trim();
+    room.settings_manager(user_id)?;
+    let reply = match (action, rest) {
+        ("list", "") => {
+            let rules = &room.settings().automod;
+            if rules.is_empty() {
+                "This room has no auto moderation rules".to_string()
+            } else {
+                rules
+                    .iter()
+                    .enumerate()
+                    .map(|(i, rule)| format!("{}. {}", i + 1, rule.describe()))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            }
+        }
+        ("remove", position) => {
+            let Ok(position) = position.parse::<usize>() else {
+                return Err(usage.to_string());
+            };
+            let removed = room.change_settings(user_id, |settings| {
+                if position == 0 || position > settings.automod.len() {
+                    return Err(format!("There is no rule {position}, see /automod list"));
+                }
+                Ok(settings.automod.remove(position - 1))
+            })??;
+            format!("Removed rule {}", removed.describe())
+        }
+        (action, rest) => {
+            let Some(action) = FilterAction::parse(action) else {
+                return Err(usage.to_string());
+            };
+            let (kind, value) = rest.split_once(' ').unwrap_or((rest, ""));
+            let matcher = match (kind, value.trim()) {
+                ("links", "") => RuleMatcher::Links,
+                ("words", words) if !words.is_empty() => RuleMatcher::Words {
+                    words: words
+                        .split(',')
+                        .map(|word| word.trim().to_string())
+                        .filter(|word| !word.is_empty())
+                        .collect(),
+                },
+                ("pattern", pattern) if !pattern.is_empty() => RuleMatcher::Pattern {
+                    pattern: pattern.to_string(),
+                },
+                _ => return Err(usage.to_string()),
+            };
+            let rule = AutoModRule { matcher, action };
+            rule.compile()?;
+            room.change_settings(user_id, |settings| {
+                if settings.automod.len() >= MAX_ROOM_RULES {
+                    return Err(format!(
+                        "Rooms can have at most {MAX_ROOM_RULES} auto moderation rules"
+                    ));
+                }
+                settings.automod.push(rule.clone());
+                Ok(())
+            })??;
+            format!("Added rule {}", rule.describe())
+        }
+    };
+    Ok(vec![CommandEffect::Reply(reply)])
+}
+
+fn settings(room: &mut Room, _user_id: Uuid, _args: &str) -> CommandResult {
+    Ok(vec![CommandEffect::Reply(room.settings().describe())])
+}
+
+fn who(room: &mut Room, _user_id: Uuid, _args: &str) -> CommandResult {
+    let mut names: Vec<String> = room
+        .member_names()
+        .into_iter()
+        .map(|name| name.to_string())
+        .collect();
+    names.sort();
+    Ok(vec![CommandEffect::Reply(format!(
+        "Members ({}): {}",
+        names.len(),
+        names.join(", ")
+    ))])
+}
+
+fn help(room: &mut Room, _user_id: Uuid, _args: &str) -> CommandResult {
+    let lines: Vec<String> = room
+        .commands()
+        .iter()
+        .map(|spec| format!("{} - {}", spec.usage, spec.description))
+        .chain(
+            room.bot_commands()
+                .map(|command| format!("{} - {}", command.usage, command.description)),
+        )
+        .collect();
+    Ok(vec![CommandEffect::Reply(format!(
+        "Available commands:\n{}",
+        lines.join("\n")
+    ))])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use actix_web::web;
+    use mongodb::Client;
+    use tokio::sync::{Mutex, mpsc};
+
+    use super::*;
+    use crate::{
+        bot::BotRegistry,
+        direct::DirectRouter,
+        filter::FilterChain,
+        roomwebserver::server::{RoomEvent, RoomServices},
+        settings::{NOT_MODERATOR, RoomSettings},
+        user::User,
+        webhook::WebhookDispatcher,
+    };
+
+    // A room with a regular member, a moderator signed in with its token and someone who only
+    // goes by the moderator's name
+    struct TestRoom {
+        room: Room,
+        member: Uuid,
+        moderator: Uuid,
+        pretender: Uuid,
+        // Kept so the room has somewhere to send what it tells its members
+        _room_rx: mpsc::Receiver<RoomEvent>,
+        _sessions: Vec<mpsc::Receiver<ServerEvent>>,
+    }
+
+    async fn room(messages: Vec<Arc<Message>>) -> TestRoom {
+        // The client only connects once something is sent, which none of these commands do
+        let database = web::Data::new(
+            Client::with_uri_str("mongodb://127.0.0.1:27017")
+                .await
+                .unwrap()
+                .database("command_tests"),
+        );
+        let users = Arc::new(Mutex::new(HashMap::new()));
+        let services = RoomServices {
+            database: database.clone(),
+            commands: web::Data::new(CommandRegistry::with_defaults()),
+            direct: web::Data::new(DirectRouter::spawn(users, database.clone())),
+            webhooks: web::Data::new(WebhookDispatcher::spawn(database)),
+            bots: web::Data::new(BotRegistry::default()),
+            filters: web::Data::new(FilterChain::default()),
+            moderators: Arc::new(HashSet::from(["mod".to_string()])),
+        };
+        let room_id = Arc::new("lobby".to_string());
+        let (mut room, room_rx) =
+            Room::spawn_room(room_id, messages, RoomSettings::default(), services);
+
+        let mut sessions = Vec::new();
+        let mut join = async |name: &str, signed_in: bool| {
+            let user_id = Uuid::new();
+            let (user_tx, user_rx) = mpsc::channel(100);
+            let (shutdown_tx, _) = tokio::sync::watch::channel(false);
+            let user = User::new(
+                user_id,
+                name.to_string(),
+                room.room_id(),
+                user_tx,
+                shutdown_tx,
+            );
+            let user = match signed_in {
+                true => user.signed_in_as(name.to_string()),
+                false => user,
+            };
+            room.add_user(Arc::new(Mutex::new(user))).await;
+            sessions.push(user_rx);
+            user_id
+        };
+        let member = join("alice", false).await;
+        let moderator = join("mod", true).await;
+        let pretender = join("mod", false).await;
+        TestRoom {
+            room,
+            member,
+            moderator,
+            pretender,
+            _room_rx: room_rx,
+            _sessions: sessions,
+        }
+    }
+
+    fn run(room: &mut Room, user_id: Uuid, txt: &str) -> CommandResult {
+        let Input::Command { name, args } = parse(txt) else {
+            panic!("{txt} is not a command");
+        };
+        let handler = room
+            .commands()
+            .get(name)
+            .map(|spec| spec.handler)
+            .ok_or_else(|| room.commands().unknown_command(name))?;
+        handler(room, user_id, args)
+    }
+
+    fn message_from(account: &str) -> Arc<Message> {
+        Arc::new(
+            Message::new(
+                Uuid::new(),
+                Arc::new(account.to_string()),
+                "hello".to_string(),
+                Arc::new("lobby".to_string()),
+            )
+            .with_author(account),
+        )
+    }
+
+    #[test]
+    fn parses_commands_and_escaped_slashes() {
+        assert_eq!(parse("hello"), Input::Text("hello"));
+        assert_eq!(
+            parse("/nick   bob "),
+            Input::Command {
+                name: "nick",
+                args: "bob"
+            }
+        );
+        assert_eq!(
+            parse("/help"),
+            Input::Command {
+                name: "help",
+                args: ""
+            }
+        );
+        assert_eq!(
+            parse("/msg bob hi there"),
+            Input::Command {
+                name: "msg",
+                args: "bob hi there"
+            }
+        );
+        assert_eq!(parse("//shrug"), Input::Text("/shrug"));
+    }
+
+    #[test]
+    fn unknown_commands_suggest_a_close_one() {
+        let registry = CommandRegistry::with_defaults();
+
+        assert_eq!(
+            registry.unknown_command("nic"),
+            "Unknown command /nic. Did you mean /nick? Type /help for a list of commands"
+        );
+        assert_eq!(
+            registry.unknown_command("zzz"),
+            "Unknown command /zzz. Type /help for a list of commands"
+        );
+        assert_eq!(
+            registry.unknown_command(""),
+            "Unknown command /. Type /help for a list of commands"
+        );
+    }
+
+    #[test]
+    fn every_command_lists_its_usage() {
+        let registry = CommandRegistry::with_defaults();
+
+        for name in [
+            "nick",
+            "me",
+            "msg",
+            "edit",
+            "delete",
+            "slowmode",
+            "invite",
+            "retention",
+        ] {
+            let spec = registry.get(name).unwrap();
+            assert!(spec.usage.starts_with(&format!("/{name}")), "{name}");
+        }
+        assert!(registry.get("shrug").is_none());
+    }
+
+    #[actix_web::test]
+    async fn unknown_commands_are_refused_in_a_room() {
+        let mut test = room(Vec::new()).await;
+
+        let refused = run(&mut test.room, test.member, "/nic bob").unwrap_err();
+
+        assert!(refused.contains("Did you mean /nick?"));
+    }
+
+    #[actix_web::test]
+    async fn nicks_are_validated() {
+        let mut test = room(Vec::new()).await;
+        let member = test.member;
+
+        assert_eq!(
+            run(&mut test.room, member, "/nick").unwrap_err(),
+            "Usage: /nick <name>"
+        );
+        assert_eq!(
+            run(&mut test.room, member, "/nick bo b").unwrap_err(),
+            "Names cannot contain spaces"
+        );
+        let too_long = "a".repeat(MAX_NICK_LENGTH + 1);
+        assert_eq!(
+            run(&mut test.room, member, &format!("/nick {too_long}")).unwrap_err(),
+            format!("Names can be at most {MAX_NICK_LENGTH} characters")
+        );
+        assert_eq!(
+            run(&mut test.room, member, "/nick mod").unwrap_err(),
+            "mod is already taken in this room"
+        );
+        assert_eq!(test.room.display_name(member).unwrap().as_str(), "alice");
+    }
+
+    #[actix_web::test]
+    async fn nicks_count_characters_rather_than_bytes() {
+        let mut test = room(Vec::new()).await;
+        let name = "é".repeat(MAX_NICK_LENGTH);
+
+        let effects = run(&mut test.room, test.member, &format!("/nick {name}")).unwrap();
+
+        assert!(matches!(&effects[0], CommandEffect::Rename(renamed) if **renamed == name));
+        assert_eq!(*test.room.display_name(test.member).unwrap(), name);
+        assert_eq!(test.room.account(test.member).unwrap().as_str(), "alice");
+    }
+
+    #[actix_web::test]
+    async fn direct_messages_need_someone_else_and_some_text() {
+        let mut test = room(Vec::new()).await;
+        let member = test.member;
+
+        for args in ["/msg", "/msg bob", "/msg bob   "] {
+            assert_eq!(
+                run(&mut test.room, member, args).unwrap_err(),
+                "Usage: /msg <user> <message>"
+            );
+        }
+        assert_eq!(
+            run(&mut test.room, member, "/msg alice hi").unwrap_err(),
+            "You cannot send a direct message to yourself"
+        );
+
+        let effects = run(&mut test.room, member, "/msg bob  hi there").unwrap();
+
+        assert!(matches!(
+            &effects[..],
+            [CommandEffect::Direct { recipient, content }]
+                if recipient == "bob" && content == "hi there"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn direct_messages_go_through_the_room_filters() {
+        let mut test = room(Vec::new()).await;
+        run(&mut test.room, test.moderator, "/automod mask words darn").unwrap();
+        run(&mut test.room, test.moderator, "/automod reject links").unwrap();
+
+        let refused = run(
+            &mut test.room,
+            test.member,
+            "/msg bob see https://example.com",
+        );
+        let effects = run(&mut test.room, test.member, "/msg bob darn it").unwrap();
+
+        assert!(
+            refused
+                .unwrap_err()
+                .starts_with("Your message was not sent")
+        );
+        assert!(matches!(
+            &effects[..],
+            [CommandEffect::Direct { content, .. }, CommandEffect::Reply(note)]
+                if !content.contains("darn") && note.starts_with("Parts of your message were masked")
+        ));
+    }
+
+    #[actix_web::test]
+    async fn slow_mode_takes_seconds_or_off() {
+        let mut test = room(Vec::new()).await;
+        let moderator = test.moderator;
+        let usage = format!("Usage: /slowmode <seconds|off>, at most {MAX_SLOW_MODE_SECS} seconds");
+
+        for args in ["/slowmode", "/slowmode soon", "/slowmode 0"] {
+            assert_eq!(run(&mut test.room, moderator, args).unwrap_err(), usage);
+        }
+        let too_long = format!("/slowmode {}", MAX_SLOW_MODE_SECS + 1);
+        assert_eq!(
+            run(&mut test.room, moderator, &too_long).unwrap_err(),
+            usage
+        );
+
+        run(&mut test.room, moderator, "/slowmode 10").unwrap();
+        assert_eq!(test.room.settings().slow_mode_secs, Some(10));
+        run(&mut test.room, moderator, "/slowmode off").unwrap();
+        assert_eq!(test.room.settings().slow_mode_secs, None);
+    }
+
+    #[actix_web::test]
+    async fn only_signed_in_moderators_change_settings() {
+        let mut test = room(Vec::new()).await;
+
+        for user_id in [test.member, test.pretender] {
+            assert_eq!(
+                run(&mut test.room, user_id, "/slowmode 10").unwrap_err(),
+                NOT_MODERATOR
+            );
+            assert_eq!(
+                run(&mut test.room, user_id, "/invite bob").unwrap_err(),
+                NOT_MODERATOR
+            );
+            assert_eq!(
+                run(&mut test.room, user_id, "/retention 30 off").unwrap_err(),
+                "Only moderators can change how long messages are kept"
+            );
+        }
+        assert_eq!(test.room.settings().slow_mode_secs, None);
+        assert!(test.room.settings().invited.is_empty());
+        assert_eq!(test.room.settings().retention_days, None);
+
+        run(&mut test.room, test.moderator, "/invite bob").unwrap();
+        run(&mut test.room, test.moderator, "/retention 30 off").unwrap();
+        assert_eq!(test.room.settings().invited, vec!["bob".to_string()]);
+        assert_eq!(test.room.settings().retention_days, Some(30));
+    }
+
+    #[actix_web::test]
+    async fn invites_and_retention_check_their_arguments() {
+        let mut test = room(Vec::new()).await;
+        let moderator = test.moderator;
+
+        for args in ["/invite", "/invite bob alice"] {
+            assert_eq!(
+                run(&mut test.room, moderator, args).unwrap_err(),
+                "Usage: /invite <account>"
+            );
+        }
+        for args in [
+            "/retention",
+            "/retention 30",
+            "/retention 0 off",
+            "/retention 30 off 5",
+        ] {
+            assert_eq!(
+                run(&mut test.room, moderator, args).unwrap_err(),
+                "Usage: /retention <days|off> <messages|off>"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_authors_and_moderators_change_messages() {
+        let theirs = message_from("bob");
+        let mut test = room(vec![Arc::clone(&theirs), message_from("alice")]).await;
+        let id = theirs.id();
+
+        for user_id in [test.member, test.pretender] {
+            assert_eq!(
+                run(&mut test.room, user_id, &format!("/edit {id} mine now")).unwrap_err(),
+                "You can only change your own messages"
+            );
+            assert_eq!(
+                run(&mut test.room, user_id, &format!("/delete {id}")).unwrap_err(),
+                "You can only change your own messages"
+            );
+        }
+        assert_eq!(test.room.find_message(id).unwrap().content(), "hello");
+
+        run(
+            &mut test.room,
+            test.moderator,
+            &format!("/edit {id} tidied"),
+        )
+        .unwrap();
+        assert_eq!(test.room.find_message(id).unwrap().content(), "tidied");
+        run(&mut test.room, test.moderator, &format!("/delete {id}")).unwrap();
+        assert!(test.room.find_message(id).unwrap().is_deleted());
+    }
+
+    #[actix_web::test]
+    async fn members_edit_their_own_messages() {
+        let mine = message_from("alice");
+        let mut test = room(vec![Arc::clone(&mine)]).await;
+        let member = test.member;
+        let id = mine.id();
+
+        for args in ["/edit", &format!("/edit {id}"), &format!("/edit {id}   ")] {
+            assert_eq!(
+                run(&mut test.room, member, args).unwrap_err(),
+                "Usage: /edit <message id> <text>"
+            );
+        }
+        assert_eq!(
+            run(&mut test.room, member, "/edit nope fixed").unwrap_err(),
+            "nope is not a valid message id"
+        );
+        assert_eq!(
+            run(
+                &mut test.room,
+                member,
+                &format!("/edit {} fixed", Uuid::new())
+            )
+            .unwrap_err(),
+            "No message with that id in this room"
+        );
+
+        run(&mut test.room, member, &format!("/edit {id} fixed")).unwrap();
+        assert_eq!(test.room.find_message(id).unwrap().content(), "fixed");
+        run(&mut test.room, member, &format!("/delete {id}")).unwrap();
+        assert_eq!(
+            run(&mut test.room, member, &format!("/edit {id} again")).unwrap_err(),
+            "That message has been deleted"
+        );
+    }
+}
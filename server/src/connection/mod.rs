@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse, rt,
+    web::{self, Payload, Query},
+};
+use actix_ws::{CloseCode, CloseReason, MessageStream};
+use mongodb::bson::Uuid;
+use tokio::sync::{
+    Mutex,
+    mpsc::{self, Receiver, Sender},
+};
+
+use crate::{
+    Err, RoomMap, UserMap,
+    apitoken::ApiTokens,
+    direct,
+    dto::ConnectDTO,
+    event::{ClientEvent, ConnectionCommand, ScopedClientEvent, ScopedEvent, ServerEvent},
+    origin::OriginPolicy,
+    roomwebserver::{
+        controller::{self, is_valid_room_id},
+        server::{Room, RoomEvent, RoomServices},
+    },
+    settings::Credentials,
+    user::User,
+};
+
+// One of the rooms a connection has joined. Rooms only know about users, so every room gets a
+// user of its own which all share the connection's id.
+struct Membership {
+    room_id: Arc<String>,
+    user: Arc<Mutex<User>>,
+    room: Weak<Mutex<Room>>,
+    room_tx: Sender<RoomEvent>,
+}
+
+/*
+ * A websocket that follows any number of rooms. The client joins and leaves rooms with
+ * {"type": "join", "room": ...} and {"type": "leave", "room": ...}, and aims everything else at
+ * a room by adding "room" to the usual client events.
+ */
+struct Connection {
+    user_id: Uuid,
+    account: Arc<String>,
+    // Signed in with an API token of the account
+    signed_in: bool,
+    rooms: web::Data<RoomMap>,
+    users: web::Data<UserMap>,
+    services: web::Data<RoomServices>,
+    memberships: HashMap<String, Membership>,
+    out_tx: Sender<ScopedEvent>,
+    // Direct messages left for the account are handed over once it can be found in a room
+    pending_delivered: bool,
+}
+
+// Every argument is an extractor actix fills in
+#[allow(clippy::too_many_arguments)]
+pub async fn connect(
+    req: HttpRequest,
+    stream: Payload,
+    details: Query<ConnectDTO>,
+    rooms: web::Data<RoomMap>,
+    users: web::Data<UserMap>,
+    services: web::Data<RoomServices>,
+    origins: web::Data<OriginPolicy>,
+    api_tokens: web::Data<ApiTokens>,
+) -> Result<HttpResponse, Err> {
+    if let Err(res) = origins.check(&req) {
+        return Ok(res);
+    }
+    let signed_in = match api_tokens
+        .sign_in(details.token.as_deref(), &services.database)
+        .await
+    {
+        Ok(signed_in) => signed_in,
+        Err(res) => return Ok(res),
+    };
+    let (res, mut session, receive_session) = match actix_ws::handle(&req, stream) {
+        Ok(tuple) => tuple,
+        Err(e) => {
+            println!("Unable to spawn websocket for user because of {e}");
+            return Err("Unable to spawn websocket. Please try again".into());
+        }
+    };
+    println!("Multi room connection opened for {}", details.username);
+
+    let (out_tx, mut out_rx) = mpsc::channel::<ScopedEvent>(64);
+    rt::spawn(async move {
+        while let Some(event) = out_rx.recv().await {
+            session
+                .text(serde_json::to_string(&event).unwrap_or("message not found".to_string()))
+                .await
+                .unwrap_or_else(|e| {
+                    println!("Channel has been closed! {e:?}");
+                });
+        }
+        println!("Closing multi room sender");
+        session
+            .close(Some(CloseReason {
+                code: CloseCode::Normal,
+                description: Some(String::from("User has closed the channel!")),
+            }))
+            .await
+            .unwrap_or_else(|e| println!("Unable to disconnect from server! {e:?}"));
+    });
+
+    let connection = Connection {
+        user_id: Uuid::new(),
+        signed_in: signed_in.is_some(),
+        account: Arc::new(signed_in.unwrap_or_else(|| details.username.to_owned())),
+        rooms,
+        users,
+        services,
+        memberships: HashMap::new(),
+        out_tx,
+        pending_delivered: false,
+    };
+    rt::spawn(connection.run(receive_session));
+    Ok(res)
+}
+
+impl Connection {
+    async fn run(mut self, mut write_session: MessageStream) {
+        while let Some(msg) = write_session.recv().await {
+            match msg {
+                Ok(actix_ws::Message::Text(txt)) => {
+                    println!("Message received! {txt}");
+                    self.read_text(&txt).await;
+                }
+                Ok(actix_ws::Message::Close(msg)) => {
+                    println!("The channel has been closed becasue of {msg:?}");
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => println!("Unable to read stream, {e:?}"),
+            }
+        }
+
+        let room_ids: Vec<String> = self.memberships.keys().cloned().collect();
+        for room_id in room_ids {
+            self.leave(room_id).await;
+        }
+    }
+
+    async fn read_text(&mut self, txt: &str) {
+        if let Ok(command) = serde_json::from_str::<ConnectionCommand>(txt) {
+            match command {
+                ConnectionCommand::Join {
+                    room,
+                    password,
+                    invite,
+                } => self.join(room, Credentials { password, invite }).await,
+                ConnectionCommand::Leave { room } => self.leave(room).await,
+            }
+            return;
+        }
+        match serde_json::from_str::<ScopedClientEvent>(txt) {
+            Ok(scoped) => self.forward(scoped.room, scoped.event).await,
+            Err(e) => {
+                self.reply(
+                    None,
+                    format!("Frames need a type and the room they are for: {e}"),
+                )
+                .await
+            }
+        }
+    }
+
+    async fn join(&mut self, room_id: String, credentials: Credentials) {
+        if !is_valid_room_id(&room_id) {
+            return self
+                .reply(
+                    Some(Arc::new(room_id)),
+                    "Room ids can only contain letters, numbers, '-' and '_' and be at most 64 characters"
+                        .to_string(),
+                )
+                .await;
+        }
+        let room_id = Arc::new(room_id);
+        // Someone removed from the room can come back
+        if let Some(membership) = self.memberships.get(room_id.as_str())
+            && membership.user.lock().await.disconnected
+        {
+            self.memberships.remove(room_id.as_str());
+        }
+        if self.memberships.contains_key(room_id.as_str()) {
+            return self
+                .reply(Some(room_id), "Already in this room".to_string())
+                .await;
+        }
+
+        let (user_tx, user_rx) = mpsc::channel::<ServerEvent>(32);
+        let pending_tx = user_tx.clone();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let user = User::new(
+            self.user_id,
+            self.account.to_string(),
+            Arc::clone(&room_id),
+            user_tx,
+            shutdown_tx,
+        );
+        let user = match self.signed_in {
+            true => user.signed_in_as(self.account.to_string()),
+            false => user,
+        };
+        let user = Arc::new(Mutex::new(user));
+
+        // Started before entering so the stored messages have somewhere to go
+        rt::spawn(forward(
+            user_rx,
+            shutdown_rx,
+            Arc::clone(&room_id),
+            self.out_tx.clone(),
+        ));
+        let room = match controller::enter_room(
+            &self.rooms,
+            &self.users,
+            &self.services,
+            &room_id,
+            Arc::clone(&user),
+            &credentials,
+        )
+        .await
+        {
+            Ok(room) => room,
+            Err(refusal) => return self.reply(Some(room_id), refusal.to_string()).await,
+        };
+        // Through the room's channel so it lands after the stored messages, marking their end
+        pending_tx
+            .send(ServerEvent::Joined)
+            .await
+            .unwrap_or_else(|e| println!("Unable to confirm join {e:?}"));
+        let Some(room_tx) = user.lock().await.room_sender() else {
+            println!("Room {room_id} did not hand out a sender");
+            return;
+        };
+        self.memberships.insert(
+            room_id.to_string(),
+            Membership {
+                room_id,
+                user,
+                room: Arc::downgrade(&room),
+                room_tx,
+            },
+        );
+        println!("Successfully added user to room!");
+
+        if !self.pending_delivered {
+            self.pending_delivered = true;
+            direct::deliver_pending(&self.services.database, &self.account, &pending_tx)
+                .await
+                .unwrap_or_else(|e| println!("Unable to deliver pending direct messages {e:?}"));
+        }
+    }
+
+    async fn leave(&mut self, room_id: String) {
+        let Some(membership) = self.memberships.remove(&room_id) else {
+            return self
+                .reply(Some(Arc::new(room_id)), "Not in this room".to_string())
+                .await;
+        };
+        User::leave_room(&membership.user, &membership.room).await;
+        self.send(Some(membership.room_id), ServerEvent::Left).await;
+    }
+
+    async fn forward(&mut self, room_id: String, event: ClientEvent) {
+        let Some(membership) = self.memberships.get(&room_id) else {
+            return self
+                .reply(Some(Arc::new(room_id)), "Join the room first".to_string())
+                .await;
+        };
+        if membership.user.lock().await.disconnected {
+            self.memberships.remove(&room_id);
+            return self
+                .reply(
+                    Some(Arc::new(room_id)),
+                    "You were removed from this room, join it again first".to_string(),
+                )
+                .await;
+        }
+        let event = User::read_event(
+            &membership.user,
+            self.user_id,
+            &self.account,
+            &membership.room_id,
+            event,
+        )
+        .await;
+        membership
+            .room_tx
+            .send(event)
+            .await
+            .unwrap_or_else(|e| println!("Unable to send message {e:?}"));
+    }
+
+    async fn reply(&self, room: Option<Arc<String>>, reason: String) {
+        self.send(room, ServerEvent::Error { reason }).await;
+    }
+
+    async fn send(&self, room: Option<Arc<String>>, event: ServerEvent) {
+        self.out_tx
+            .send(ScopedEvent::new(room, event))
+            .await
+            .unwrap_or_else(|e| println!("Unable to send to connection {e:?}"));
+    }
+}
+
+/*
+ * Tags everything a room sends to the connection's user for it until they leave
+ */
+async fn forward(
+    mut user_rx: Receiver<ServerEvent>,
+    shutdown_rx: tokio::sync::watch::Receiver<bool>,
+    room_id: Arc<String>,
+    out_tx: Sender<ScopedEvent>,
+) {
+    while let Some(event) = user_rx.recv().await {
+        // Removed from the room, the rest of the connection carries on
+        if let ServerEvent::Closing { .. } = event {
+            out_tx
+                .send(ScopedEvent::new(Some(room_id), event))
+                .await
+                .unwrap_or_else(|e| println!("Unable to send to connection {e:?}"));
+            break;
+        }
+        if shutdown_rx.has_changed().unwrap_or(true) {
+            break;
+        }
+        if out_tx
+            .send(ScopedEvent::new(Some(Arc::clone(&room_id)), event))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{collections::HashSet, sync::Arc};
+
+use actix_web::web;
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database,
+    bson::{DateTime, Uuid, doc},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, Sender};
+
+use crate::{Err, UserMap, event::ServerEvent, message::Message};
+
+pub const DIRECT_COLLECTION: &str = "direct_messages";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DirectMessage {
+    id: Uuid,
+    pub sender: String,
+    pub recipient: String,
+    content: String,
+    // Milliseconds since the unix epoch
+    sent_at: i64,
+    // False while the recipient has not had it pushed to any of their sessions yet
+    pub delivered: bool,
+}
+
+impl DirectMessage {
+    pub fn new(sender: String, recipient: String, content: String) -> DirectMessage {
+        DirectMessage {
+            id: Uuid::new(),
+            sender,
+            recipient,
+            content,
+            sent_at: DateTime::now().timestamp_millis(),
+            delivered: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Routed {
+    Direct(DirectMessage),
+    // A room message that mentioned the account, pushed to every session it has
+    Mention { account: String, msg: Arc<Message> },
+}
+
+/*
+ * Direct messages and mentions are not bound to a room, so they go through their own task
+ * which can look up every session of the user in the user map without holding any room lock.
+ */
+#[derive(Debug, Clone)]
+pub struct DirectRouter {
+    sender: Sender<Routed>,
+}
+
+impl DirectRouter {
+    pub fn spawn(users: UserMap, database: web::Data<Database>) -> DirectRouter {
+        let (direct_tx, mut direct_rx) = mpsc::channel::<Routed>(100);
+        tokio::spawn(async move {
+            while let Some(routed) = direct_rx.recv().await {
+                match routed {
+                    Routed::Direct(dm) => route(&users, &database, dm).await,
+                    Routed::Mention { account, msg } => route_mention(&users, &account, msg).await,
+                }
+            }
+        });
+        DirectRouter { sender: direct_tx }
+    }
+
+    pub async fn send(&self, dm: DirectMessage) -> Result<(), Err> {
+        if self.sender.send(Routed::Direct(dm)).await.is_err() {
+            return Err("Direct message router has stopped".into());
+        }
+        Ok(())
+    }
+
+    pub async fn mention(&self, account: String, msg: Arc<Message>) -> Result<(), Err> {
+        if self
+            .sender
+            .send(Routed::Mention { account, msg })
+            .await
+            .is_err()
+        {
+            return Err("Direct message router has stopped".into());
+        }
+        Ok(())
+    }
+}
+
+async fn sessions_for(users: &UserMap, account: &str) -> Vec<Sender<ServerEvent>> {
+    let user_lock = users.lock().await;
+    let mut sessions = Vec::new();
+    let mut seen = HashSet::new();
+    for room_users in user_lock.values() {
+        for user in room_users {
+            let user = user.lock().await;
+            // A multi room connection is in the map once per room but should only get it once
+            if !user.disconnected && user.account.as_str() == account && seen.insert(user.user_id) {
+                sessions.push(user.user_session_tx.clone());
+            }
+        }
+    }
+    sessions
+}
+
+async fn route_mention(users: &UserMap, account: &str, msg: Arc<Message>) {
+    for session in sessions_for(users, account).await {
+        session
+            .send(ServerEvent::Mention(Arc::clone(&msg)))
+            .await
+            .unwrap_or_else(|e| println!("Unable to deliver mention {e:?}"));
+    }
+}
+
+async fn route(users: &UserMap, database: &Database, mut dm: DirectMessage) {
+    let recipient_sessions = sessions_for(users, &dm.recipient).await;
+    dm.delivered = !recipient_sessions.is_empty();
+
+    let collection: Collection<DirectMessage> = database.collection(DIRECT_COLLECTION);
+    collection
+        .insert_one(&dm)
+        .await
+        .map(|_| ())
+        .unwrap_or_else(|e| println!("Unable to store direct message {e:?}"));
+
+    // The sender gets a copy too so every one of their sessions shows the conversation
+    let sender_sessions = sessions_for(users, &dm.sender).await;
+    let dm = Arc::new(dm);
+    for session in recipient_sessions.into_iter().chain(sender_sessions) {
+        session
+            .send(ServerEvent::Direct(Arc::clone(&dm)))
+            .await
+            .unwrap_or_else(|e| println!("Unable to deliver direct message {e:?}"));
+    }
+}
+
+/*
+ * Pushes everything that was sent to the account while it had no session open and marks it
+ * as delivered.
+ */
+pub async fn deliver_pending(
+    database: &Database,
+    account: &str,
+    session_tx: &Sender<ServerEvent>,
+) -> Result<(), Err> {
+    let collection: Collection<DirectMessage> = database.collection(DIRECT_COLLECTION);
+    let filter = doc! {"recipient": account, "delivered": false};
+    let pending: Vec<DirectMessage> = collection
+        .find(filter)
+        .sort(doc! {"sent_at": 1})
+        .await?
+        .try_collect()
+        .await?;
+
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    // Only the ones actually pushed get marked, anything routed in the meantime is left alone
+    let mut delivered_ids = Vec::new();
+    for mut dm in pending {
+        dm.delivered = true;
+        let id = dm.id;
+        if session_tx
+            .send(ServerEvent::Direct(Arc::new(dm)))
+            .await
+            .is_err()
+        {
+            break;
+        }
+        delivered_ids.push(id);
+    }
+    collection
+        .update_many(
+            doc! {"id": {"$in": delivered_ids}},
+            doc! {"$set": {"delivered": true}},
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn history(
+    database: &Database,
+    account: &str,
+    other: &str,
+) -> Result<Vec<DirectMessage>, Err> {
+    let collection: Collection<DirectMessage> = database.collection(DIRECT_COLLECTION);
+    let filter = doc! {
+        "$or": [
+            {"sender": account, "recipient": other},
+            {"sender": other, "recipient": account},
+        ]
+    };
+    let messages = collection
+        .find(filter)
+        .sort(doc! {"sent_at": 1})
+        .await?
+        .try_collect()
+        .await?;
+    Ok(messages)
+}
@@ -1,8 +1,169 @@
 use serde::{Deserialize, Serialize};
 
-
 #[derive(Serialize, Deserialize)]
 pub struct RoomInfoDTO {
     pub room_id: String,
-    pub username: String
-}
\ No newline at end of file
+    pub username: String,
+    // Only needed for password protected or invite only rooms
+    pub password: Option<String>,
+    pub invite: Option<String>,
+    // API token to sign in with, the only way to join as a moderator
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConnectDTO {
+    pub username: String,
+    // API token to sign in with, the only way to join as a moderator
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateInviteDTO {
+    pub expires_in_secs: Option<u64>,
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PostMessageDTO {
+    pub content: String,
+    // Sending the same id again within a few minutes does not post the message twice
+    pub client_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PostedMessageDTO {
+    pub id: String,
+    pub room_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MessagesQueryDTO {
+    // Sequence number of the last message already seen
+    pub after: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+// Readers are known by their token, the password is for password protected rooms
+#[derive(Serialize, Deserialize)]
+pub struct ReadQueryDTO {
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportQueryDTO {
+    // json, csv or md, json when not given
+    pub format: Option<String>,
+    // Needed for password protected rooms
+    pub password: Option<String>,
+    // Milliseconds since the unix epoch or an RFC 3339 date
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchQueryDTO {
+    pub q: String,
+    // Only used when searching a single password protected room
+    pub password: Option<String>,
+    pub sender: Option<String>,
+    // Milliseconds since the unix epoch or an RFC 3339 date
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchHitDTO {
+    pub room_id: String,
+    pub id: String,
+    pub seq: i64,
+    pub sender: String,
+    pub sent_at: i64,
+    // Words around the match, with matching words wrapped in **
+    pub snippet: String,
+    pub score: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DirectoryQueryDTO {
+    // Unread counts are worked out for this account when it is given
+    pub account: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoomDirectoryEntryDTO {
+    pub room_id: String,
+    // Whether the room is currently open on the server
+    pub open: bool,
+    pub members: usize,
+    pub topic: Option<String>,
+    pub latest_seq: i64,
+    pub unread: usize,
+    // Only moderators can post
+    pub read_only: bool,
+    // Seconds members wait between messages
+    pub slow_mode_secs: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminMemberDTO {
+    pub user_id: String,
+    pub account: String,
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminRoomDTO {
+    pub room_id: String,
+    pub members: Vec<AdminMemberDTO>,
+    pub topic: Option<String>,
+    pub latest_seq: i64,
+    // Messages only held in memory so far
+    pub unsaved: usize,
+    pub legal_hold: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AnnouncementDTO {
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReasonQueryDTO {
+    // Shown to whoever gets disconnected
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModerationLogQueryDTO {
+    // Every room when not given
+    pub room_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateServiceAccountDTO {
+    pub account: String,
+    // Room ids, or "*" for every room
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub post: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ServiceAccountDTO {
+    pub account: String,
+    pub read: Vec<String>,
+    pub post: Vec<String>,
+    pub created_at: i64,
+    // Only set in the answer to creating the account
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminActionDTO {
+    // Rooms, users or messages the action went through
+    pub affected: usize,
+}
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use mongodb::bson::Uuid;
+use serde::{Deserialize, Serialize};
+
+use crate::{direct::DirectMessage, message::Message};
+
+// Everything the server pushes down a user's websocket. Tagged with "type" so the client can
+// tell the events apart, while a message still carries its sender and content at the top level.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Message(Arc<Message>),
+    Direct(Arc<DirectMessage>),
+    // Both carry the whole updated message so clients can redraw it in place by id
+    MessageEdited(Arc<Message>),
+    MessageDeleted(Arc<Message>),
+    // Sent to every session of a mentioned account on top of the message itself, without
+    // the content when the account cannot read the room
+    Mention(Arc<Message>),
+    // Only the change is sent, clients apply it to the reactions they already have
+    Reaction {
+        message_id: Uuid,
+        emoji: String,
+        account: String,
+        added: bool,
+    },
+    ThreadUpdated {
+        message_id: Uuid,
+        reply_count: u32,
+    },
+    // Never stored, only sent to the other members of the room
+    Typing {
+        user: Arc<String>,
+        active: bool,
+    },
+    ReadReceipt {
+        user: Arc<String>,
+        account: Arc<String>,
+        seq: i64,
+    },
+    // Tells the sender which message their client id ended up as
+    Ack {
+        client_id: String,
+        id: Uuid,
+        seq: i64,
+    },
+    // Tells the sender a message they sent with a client id did not go out, so the client can
+    // stop waiting on it
+    Rejected {
+        client_id: String,
+        reason: String,
+    },
+    // What the member can post, sent on joining and whenever the room changes it so clients can
+    // disable their input. Slow mode is left out for members it does not apply to.
+    RoomMode {
+        can_post: bool,
+        slow_mode_secs: Option<u32>,
+    },
+    // Answers to join and leave on a multi room connection, the room is in the tag around them
+    Joined,
+    Left,
+    Error {
+        reason: String,
+    },
+    // Last thing a removed user gets, single room connections are closed with the reason
+    Closing {
+        reason: String,
+    },
+}
+
+// What goes down a multi room connection. Events from a room are tagged with it so the client
+// can tell the rooms apart, direct messages and mentions are not tied to one so they go untagged.
+#[derive(Serialize, Debug)]
+pub struct ScopedEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<Arc<String>>,
+    #[serde(flatten)]
+    event: ServerEvent,
+}
+
+impl ScopedEvent {
+    pub fn new(room: Option<Arc<String>>, event: ServerEvent) -> ScopedEvent {
+        let room = match event {
+            ServerEvent::Direct(_) | ServerEvent::Mention(_) => None,
+            _ => room,
+        };
+        ScopedEvent { room, event }
+    }
+}
+
+// Structured frames a client can send instead of plain text. Anything that does not parse as
+// one of these is treated as text typed into the room.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    Typing { active: bool },
+    // Everything up to and including seq has been on the user's screen
+    Read { seq: i64 },
+    // Text with an id picked by the client so that sending it again does not repeat it
+    Send { client_id: String, content: String },
+}
+
+// Frames that manage which rooms a multi room connection follows
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConnectionCommand {
+    Join {
+        room: String,
+        password: Option<String>,
+        invite: Option<String>,
+    },
+    Leave {
+        room: String,
+    },
+}
+
+// Any client event aimed at one of the rooms a multi room connection has joined
+#[derive(Deserialize, Debug)]
+pub struct ScopedClientEvent {
+    pub room: String,
+    #[serde(flatten)]
+    pub event: ClientEvent,
+}
@@ -0,0 +1,417 @@
+use std::{io::Write, pin::pin};
+
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt, TryStreamExt, stream};
+use mongodb::{
+    Collection, Cursor, Database,
+    bson::{DateTime, Document, Uuid, doc},
+};
+use serde::Serialize;
+
+use crate::{
+    Err,
+    message::{Message, MessageKind},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Markdown,
+}
+
+// One entry of a transcript, only what a reader of it needs
+#[derive(Serialize)]
+struct ExportedMessage<'a> {
+    id: Uuid,
+    seq: i64,
+    sent_at: String,
+    sender: &'a str,
+    author: &'a str,
+    kind: MessageKind,
+    content: &'a str,
+    reply_to: Option<Uuid>,
+    edited: bool,
+    deleted: bool,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<ExportFormat> {
+        match format {
+            "json" => Some(ExportFormat::Json),
+            "csv" => Some(ExportFormat::Csv),
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Markdown => "md",
+        }
+    }
+
+    fn header(self, room_id: &str) -> String {
+        match self {
+            ExportFormat::Json => "[".to_string(),
+            ExportFormat::Csv => "seq,sent_at,sender,kind,content,reply_to\n".to_string(),
+            ExportFormat::Markdown => format!("# {room_id}\n\n"),
+        }
+    }
+
+    fn row(self, msg: &Message, first: bool) -> String {
+        let sent_at = timestamp(msg.sent_at());
+        let content = match msg.is_deleted() {
+            true => "[message deleted]",
+            false => msg.content(),
+        };
+        match self {
+            ExportFormat::Json => {
+                let exported = ExportedMessage {
+                    id: msg.id(),
+                    seq: msg.seq(),
+                    sent_at,
+                    sender: &msg.sender,
+                    author: msg.author(),
+                    kind: msg.kind(),
+                    content,
+                    reply_to: msg.reply_to(),
+                    edited: msg.is_edited(),
+                    deleted: msg.is_deleted(),
+                };
+                let separator = if first { "\n" } else { ",\n" };
+                format!(
+                    "{separator}{}",
+                    serde_json::to_string(&exported).unwrap_or_default()
+                )
+            }
+            ExportFormat::Csv => {
+                let kind = match msg.kind() {
+                    MessageKind::Chat => "chat",
+                    MessageKind::Action => "action",
+                    MessageKind::System => "system",
+                };
+                let reply_to = msg.reply_to().map(|id| id.to_string()).unwrap_or_default();
+                format!(
+                    "{},{},{},{kind},{},{reply_to}\n",
+                    msg.seq(),
+                    csv_field(&sent_at),
+                    csv_field(&msg.sender),
+                    csv_field(content)
+                )
+            }
+            ExportFormat::Markdown => {
+                let thread = if msg.reply_to().is_some() { "↳ " } else { "" };
+                let edited = if msg.is_edited() { " _(edited)_" } else { "" };
+                let line = match msg.kind() {
+                    MessageKind::Action => format!("* {} {content}", msg.sender),
+                    MessageKind::System => format!("_{content}_"),
+                    MessageKind::Chat => format!("**{}**: {content}", msg.sender),
+                };
+                format!("- `{sent_at}` {thread}{line}{edited}\n")
+            }
+        }
+    }
+
+    fn footer(self) -> String {
+        match self {
+            ExportFormat::Json => "\n]\n".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+// Messages stored before they kept the time get an empty timestamp
+fn timestamp(millis: i64) -> String {
+    if millis == 0 {
+        return String::new();
+    }
+    DateTime::from_millis(millis)
+        .try_to_rfc3339_string()
+        .unwrap_or_default()
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    value.to_string()
+}
+
+/*
+ * Either milliseconds since the unix epoch or an RFC 3339 date like 2024-05-01T00:00:00Z
+ */
+pub fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(millis) = value.parse::<i64>() {
+        return Ok(millis);
+    }
+    DateTime::parse_rfc3339_str(value)
+        .map(|time| time.timestamp_millis())
+        .map_err(|_| format!("{value} is not a timestamp or an RFC 3339 date"))
+}
+
+/*
+ * Stored messages of the room sent between from and to, oldest first. Messages from before
+ * rooms numbered them all have seq 0, so those fall back to the order they were stored in.
+ */
+pub async fn find_messages(
+    database: &Database,
+    room_id: &str,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> Result<Cursor<Message>, Err> {
+    let mut filter = doc! {"room_id": room_id};
+    let mut sent_at = Document::new();
+    if let Some(from) = from {
+        sent_at.insert("$gte", from);
+    }
+    if let Some(to) = to {
+        sent_at.insert("$lte", to);
+    }
+    if !sent_at.is_empty() {
+        filter.insert("sent_at", sent_at);
+    }
+    let collection: Collection<Message> = database.collection("messages");
+    let cursor = collection
+        .find(filter)
+        .sort(doc! {"seq": 1, "_id": 1})
+        .await?;
+    Ok(cursor)
+}
+
+/*
+ * Turns the cursor into the transcript a piece at a time, so only one message is ever held
+ */
+pub fn render(
+    format: ExportFormat,
+    room_id: String,
+    cursor: Cursor<Message>,
+) -> impl Stream<Item = Result<Bytes, mongodb::error::Error>> {
+    let header = stream::once(async move { Ok(Bytes::from(format.header(&room_id))) });
+    let rows = cursor
+        .enumerate()
+        .map(move |(indx, msg)| msg.map(|msg| Bytes::from(format.row(&msg, indx == 0))));
+    let footer = stream::once(async move { Ok(Bytes::from(format.footer())) });
+    header.chain(rows).chain(footer)
+}
+
+/*
+ * server export <room_id> [--format json|csv|md] [--from <time>] [--to <time>]
+ * Writes the transcript to stdout.
+ */
+pub async fn run_cli(database: &Database, args: &[String]) -> Result<(), Err> {
+    let usage =
+        "Usage: server export <room_id> [--format json|csv|md] [--from <time>] [--to <time>]";
+    let Some(room_id) = args.first() else {
+        return Err(usage.into());
+    };
+    let mut format = ExportFormat::Json;
+    let mut from = None;
+    let mut to = None;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let Some(value) = options.next() else {
+            return Err(usage.into());
+        };
+        match option.as_str() {
+            "--format" => {
+                format = ExportFormat::parse(value)
+                    .ok_or_else(|| format!("Unknown format {value}, use json, csv or md"))?
+            }
+            "--from" => from = Some(parse_time(value)?),
+            "--to" => to = Some(parse_time(value)?),
+            _ => return Err(usage.into()),
+        }
+    }
+
+    let cursor = find_messages(database, room_id, from, to).await?;
+    let mut transcript = pin!(render(format, room_id.to_string(), cursor));
+    let mut stdout = std::io::stdout().lock();
+    while let Some(bytes) = transcript.try_next().await? {
+        stdout.write_all(&bytes)?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    fn message(sender: &str, content: &str, seq: i64) -> Message {
+        let mut msg = Message::new(
+            Uuid::new(),
+            Arc::new(sender.to_string()),
+            content.to_string(),
+            Arc::new("lobby".to_string()),
+        )
+        .with_author(sender);
+        msg.set_seq(seq);
+        msg
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("hello there"), "hello there");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("two\r\nlines"), "\"two\r\nlines\"");
+    }
+
+    #[test]
+    fn times_are_millis_or_rfc3339() {
+        assert_eq!(parse_time("1714521600000"), Ok(1714521600000));
+        assert_eq!(parse_time("0"), Ok(0));
+        assert_eq!(parse_time("2024-05-01T00:00:00Z"), Ok(1714521600000));
+        assert_eq!(parse_time("2024-05-01T02:00:00+02:00"), Ok(1714521600000));
+        assert_eq!(
+            parse_time("yesterday"),
+            Err("yesterday is not a timestamp or an RFC 3339 date".to_string())
+        );
+        assert!(parse_time("2024-05-01").is_err());
+        assert!(parse_time("").is_err());
+    }
+
+    #[test]
+    fn messages_without_a_time_get_an_empty_timestamp() {
+        assert_eq!(timestamp(0), "");
+        assert_eq!(timestamp(1714521600000), "2024-05-01T00:00:00Z");
+    }
+
+    #[test]
+    fn formats_are_picked_by_name() {
+        assert_eq!(ExportFormat::parse("json"), Some(ExportFormat::Json));
+        assert_eq!(ExportFormat::parse("csv"), Some(ExportFormat::Csv));
+        assert_eq!(ExportFormat::parse("md"), Some(ExportFormat::Markdown));
+        assert_eq!(
+            ExportFormat::parse("markdown"),
+            Some(ExportFormat::Markdown)
+        );
+        assert_eq!(ExportFormat::parse("pdf"), None);
+    }
+
+    #[test]
+    fn csv_rows_quote_what_they_carry() {
+        let root = message("alice", "hi", 1);
+        let msg = message("bob, jr", "say \"hi\"\nagain", 3).in_thread(root.id());
+
+        let row = ExportFormat::Csv.row(&msg, false);
+
+        assert_eq!(
+            row,
+            format!(
+                "3,{},\"bob, jr\",chat,\"say \"\"hi\"\"\nagain\",{}\n",
+                timestamp(msg.sent_at()),
+                root.id()
+            )
+        );
+        assert_eq!(
+            ExportFormat::Csv.header("lobby"),
+            "seq,sent_at,sender,kind,content,reply_to\n"
+        );
+    }
+
+    #[test]
+    fn deleted_messages_keep_their_place_but_not_their_content() {
+        let msg = message("alice", "secret", 1).tombstone("alice");
+
+        for format in [
+            ExportFormat::Json,
+            ExportFormat::Csv,
+            ExportFormat::Markdown,
+        ] {
+            let row = format.row(&msg, true);
+            assert!(row.contains("[message deleted]"), "{format:?}");
+            assert!(!row.contains("secret"), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn markdown_rows_show_the_kind_of_message() {
+        let sent_at = |msg: &Message| timestamp(msg.sent_at());
+        let chat = message("alice", "hi", 1);
+        let action = Message::with_kind(
+            Uuid::new(),
+            Arc::new("alice".to_string()),
+            "waves".to_string(),
+            Arc::new("lobby".to_string()),
+            MessageKind::Action,
+        );
+        let system = Message::system("alice joined".to_string(), Arc::new("lobby".to_string()));
+        let reply = message("bob", "hello", 2)
+            .in_thread(chat.id())
+            .edited("hello!".to_string(), "bob");
+
+        let row = |msg: &Message| ExportFormat::Markdown.row(msg, false);
+
+        assert_eq!(
+            row(&chat),
+            format!("- `{}` **alice**: hi\n", sent_at(&chat))
+        );
+        assert_eq!(
+            row(&action),
+            format!("- `{}` * alice waves\n", sent_at(&action))
+        );
+        assert_eq!(
+            row(&system),
+            format!("- `{}` _alice joined_\n", sent_at(&system))
+        );
+        assert_eq!(
+            row(&reply),
+            format!("- `{}` ↳ **bob**: hello! _(edited)_\n", sent_at(&reply))
+        );
+        assert_eq!(ExportFormat::Markdown.header("lobby"), "# lobby\n\n");
+    }
+
+    #[test]
+    fn json_rows_make_up_an_array() {
+        let first = message("alice", "hi", 1);
+        let second = message("bob", "hello", 2).edited("hello!".to_string(), "bob");
+        let format = ExportFormat::Json;
+
+        let transcript = format.header("lobby")
+            + &format.row(&first, true)
+            + &format.row(&second, false)
+            + &format.footer();
+        let exported: Value = serde_json::from_str(&transcript).unwrap();
+
+        let exported = exported.as_array().unwrap();
+        assert_eq!(exported.len(), 2);
+        assert_eq!(exported[0]["id"], first.id().to_string());
+        assert_eq!(exported[0]["seq"], 1);
+        assert_eq!(exported[0]["sender"], "alice");
+        assert_eq!(exported[0]["content"], "hi");
+        assert_eq!(exported[0]["edited"], false);
+        assert_eq!(exported[1]["content"], "hello!");
+        assert_eq!(exported[1]["edited"], true);
+        assert_eq!(exported[1]["reply_to"], Value::Null);
+    }
+
+    #[test]
+    fn empty_transcripts_are_still_valid_json() {
+        let format = ExportFormat::Json;
+
+        let transcript = format.header("lobby") + &format.footer();
+
+        assert_eq!(
+            serde_json::from_str::<Value>(&transcript).unwrap(),
+            Value::Array(Vec::new())
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use std::sync::Arc;
+
+use mongodb::{
+    Collection, Database,
+    bson::{DateTime, Uuid},
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+pub const MODERATION_LOG_COLLECTION: &str = "moderation_log";
+pub const MAX_ROOM_RULES: usize = 10;
+// Written over every character of a masked match
+pub const MASK: char = '*';
+
+// Bare domains on these endings count as links on top of anything starting with a scheme or www
+const LINK_PATTERN: &str = r"(?i)\b(?:[a-z][a-z0-9+.-]*://|www\.)\S+|\b[a-z0-9-]+(?:\.[a-z0-9-]+)*\.(?:com|net|org|io|dev|app|gg|ly|me|co|xyz|info|biz)\b(?:/\S*)?";
+
+// What happens to a message a filter matched, weakest first so the strongest wins
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    // Goes through as is but ends up in the moderation log
+    Flag,
+    // Goes through with the matches starred out
+    Mask,
+    // Never reaches the room
+    Reject,
+}
+
+impl FilterAction {
+    pub fn parse(action: &str) -> Option<FilterAction> {
+        match action {
+            "flag" => Some(FilterAction::Flag),
+            "mask" => Some(FilterAction::Mask),
+            "reject" => Some(FilterAction::Reject),
+            _ => None,
+        }
+    }
+}
+
+// What a room rule looks for, kept with the room settings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum RuleMatcher {
+    // Whole words, in any case
+    Words { words: Vec<String> },
+    Pattern { pattern: String },
+    Links,
+}
+
+// Auto moderation rule a moderator set up for a room
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AutoModRule {
+    pub matcher: RuleMatcher,
+    pub action: FilterAction,
+}
+
+impl AutoModRule {
+    pub fn describe(&self) -> String {
+        let action = match self.action {
+            FilterAction::Flag => "flag",
+            FilterAction::Mask => "mask",
+            FilterAction::Reject => "reject",
+        };
+        match &self.matcher {
+            RuleMatcher::Words { words } => format!("{action} words {}", words.join(",")),
+            RuleMatcher::Pattern { pattern } => format!("{action} pattern {pattern}"),
+            RuleMatcher::Links => format!("{action} links"),
+        }
+    }
+
+    /*
+     * Fails on patterns that do not compile, so bad rules are turned away when they are added
+     */
+    pub fn compile(&self) -> Result<Filter, String> {
+        let (name, pattern) = match &self.matcher {
+            RuleMatcher::Words { words } => ("room word list", words_pattern(words)),
+            RuleMatcher::Pattern { pattern } => ("room pattern", format!("(?i){pattern}")),
+            RuleMatcher::Links => ("room link rule", LINK_PATTERN.to_string()),
+        };
+        Filter::new(name, &pattern, self.action)
+    }
+}
+
+fn words_pattern(words: &[String]) -> String {
+    let words: Vec<String> = words.iter().map(|word| regex::escape(word)).collect();
+    format!(r"(?i)\b(?:{})\b", words.join("|"))
+}
+
+// One link of the chain
+#[derive(Debug)]
+pub struct Filter {
+    name: &'static str,
+    regex: Regex,
+    action: FilterAction,
+}
+
+impl Filter {
+    fn new(name: &'static str, pattern: &str, action: FilterAction) -> Result<Filter, String> {
+        let regex = Regex::new(pattern).map_err(|e| format!("Invalid pattern: {e}"))?;
+        Ok(Filter {
+            name,
+            regex,
+            action,
+        })
+    }
+}
+
+// What the chain made of a message
+#[derive(Debug)]
+pub struct Screening {
+    pub content: String,
+    // Strongest action of the filters that matched, none when nothing did
+    pub action: Option<FilterAction>,
+    pub matched: Vec<&'static str>,
+}
+
+impl Screening {
+    /*
+     * Told to the sender whenever something was done about their message
+     */
+    pub fn note(&self) -> Option<String> {
+        let reasons = self.matched.join(", ");
+        match self.action? {
+            FilterAction::Reject => Some(format!("Your message was not sent ({reasons})")),
+            FilterAction::Mask => Some(format!("Parts of your message were masked ({reasons})")),
+            FilterAction::Flag => Some(format!(
+                "Your message was flagged for review by the moderators ({reasons})"
+            )),
+        }
+    }
+}
+
+/*
+ * Filters every message members send goes through before it reaches the room, the server wide
+ * ones from the environment followed by the room's own rules
+ */
+#[derive(Debug, Default)]
+pub struct FilterChain {
+    filters: Vec<Arc<Filter>>,
+}
+
+impl FilterChain {
+    /*
+     * FILTER_WORDS is a comma separated word list and FILTER_PATTERNS_FILE a file with a regex
+     * on every line, both masked. BLOCK_LINKS=true rejects messages with links.
+     */
+    pub fn from_env() -> FilterChain {
+        let mut chain = FilterChain::default();
+        let words: Vec<String> = std::env::var("FILTER_WORDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|word| word.trim().to_string())
+            .filter(|word| !word.is_empty())
+            .collect();
+        if !words.is_empty() {
+            chain.push(Filter::new(
+                "word list",
+                &words_pattern(&words),
+                FilterAction::Mask,
+            ));
+        }
+
+        if let Ok(path) = std::env::var("FILTER_PATTERNS_FILE") {
+            match std::fs::read_to_string(&path) {
+                Ok(patterns) => {
+                    for pattern in patterns.lines().map(str::trim) {
+                        if !pattern.is_empty() && !pattern.starts_with('#') {
+                            chain.push(Filter::new("pattern", pattern, FilterAction::Mask));
+                        }
+                    }
+                }
+                Err(e) => println!("Unable to read filter patterns from {path} {e:?}"),
+            }
+        }
+
+        if std::env::var("BLOCK_LINKS").is_ok_and(|block| block == "true") {
+            chain.push(Filter::new("links", LINK_PATTERN, FilterAction::Reject));
+        }
+        println!("Filtering messages through {} filters", chain.filters.len());
+        chain
+    }
+
+    fn push(&mut self, filter: Result<Filter, String>) {
+        match filter {
+            Ok(filter) => self.filters.push(Arc::new(filter)),
+            Err(e) => println!("Skipping filter {e}"),
+        }
+    }
+
+    /*
+     * This chain followed by the room's rules. Rules that stopped compiling are skipped.
+     */
+    pub fn with_room_rules(&self, rules: &[AutoModRule]) -> FilterChain {
+        let mut chain = FilterChain {
+            filters: self.filters.clone(),
+        };
+        for rule in rules {
+            chain.push(rule.compile());
+        }
+        chain
+    }
+
+    pub fn screen(&self, content: &str) -> Screening {
+        let mut screening = Screening {
+            content: content.to_string(),
+            action: None,
+            matched: Vec::new(),
+        };
+        for filter in self.filters.iter() {
+            // Matched against what was sent, so masking by an earlier filter hides nothing
+            if !filter.regex.is_match(content) {
+                continue;
+            }
+            if !screening.matched.contains(&filter.name) {
+                screening.matched.push(filter.name);
+            }
+            screening.action = screening.action.max(Some(filter.action));
+            if filter.action == FilterAction::Mask {
+                screening.content = filter
+                    .regex
+                    .replace_all(&screening.content, |found: &regex::Captures| {
+                        MASK.to_string().repeat(found[0].chars().count())
+                    })
+                    .into_owned();
+            }
+        }
+        screening
+    }
+}
+
+// Kept for moderators whenever a filter did something about a message
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ModerationLogEntry {
+    pub room_id: String,
+    pub account: String,
+    pub action: FilterAction,
+    pub filters: Vec<String>,
+    // What the member sent, before any masking
+    pub content: String,
+    // Id the message went out with, unset when it was rejected
+    pub message_id: Option<String>,
+    // Milliseconds since the unix epoch
+    pub logged_at: i64,
+}
+
+impl ModerationLogEntry {
+    /*
+     * Entry for what the account sent, none when no filter matched. The message id is only
+     * kept when the content went through.
+     */
+    pub fn new(
+        room_id: &str,
+        account: &str,
+        content: &str,
+        message_id: Option<Uuid>,
+        screening: &Screening,
+    ) -> Option<ModerationLogEntry> {
+        Some(ModerationLogEntry {
+            room_id: room_id.to_string(),
+            account: account.to_string(),
+            action: screening.action?,
+            filters: screening
+                .matched
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            content: content.to_string(),
+            message_id: message_id.map(|id| id.to_string()),
+            logged_at: DateTime::now().timestamp_millis(),
+        })
+    }
+}
+
+/*
+ * Written from a task of its own so the room is not held up by the store
+ */
+pub fn log(database: &Database, entry: ModerationLogEntry) {
+    println!(
+        "Filters {:?} took action {:?} on a message from {} in {}",
+        entry.filters, entry.action, entry.account, entry.room_id
+    );
+    let collection: Collection<ModerationLogEntry> = database.collection(MODERATION_LOG_COLLECTION);
+    tokio::spawn(async move {
+        collection
+            .insert_one(&entry)
+            .await
+            .map(|_| ())
+            .unwrap_or_else(|e| println!("Unable to store moderation log entry {e:?}"));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(matcher: RuleMatcher, action: FilterAction) -> AutoModRule {
+        AutoModRule { matcher, action }
+    }
+
+    fn words(words: &[&str]) -> RuleMatcher {
+        RuleMatcher::Words {
+            words: words.iter().map(|word| word.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn masks_whole_words_in_any_case() {
+        let chain =
+            FilterChain::default().with_room_rules(&[rule(words(&["darn"]), FilterAction::Mask)]);
+        let screening = chain.screen("Darn it, darned thing");
+        assert_eq!(screening.action, Some(FilterAction::Mask));
+        assert_eq!(screening.content, "**** it, darned thing");
+        assert_eq!(screening.matched, vec!["room word list"]);
+        assert!(screening.note().is_some());
+    }
+
+    #[test]
+    fn leaves_clean_messages_alone() {
+        let chain =
+            FilterChain::default().with_room_rules(&[rule(words(&["darn"]), FilterAction::Mask)]);
+        let screening = chain.screen("hello there");
+        assert_eq!(screening.action, None);
+        assert_eq!(screening.content, "hello there");
+        assert!(screening.note().is_none());
+    }
+
+    #[test]
+    fn strongest_action_wins() {
+        let chain = FilterChain::default().with_room_rules(&[
+            rule(words(&["darn"]), FilterAction::Mask),
+            rule(RuleMatcher::Links, FilterAction::Reject),
+            rule(words(&["sale"]), FilterAction::Flag),
+        ]);
+        let screening = chain.screen("darn sale at example.com/deals");
+        assert_eq!(screening.action, Some(FilterAction::Reject));
+        assert_eq!(screening.matched, vec!["room word list", "room link rule"]);
+        assert_eq!(chain.screen("big sale").action, Some(FilterAction::Flag));
+    }
+
+    #[test]
+    fn masking_does_not_hide_later_matches() {
+        let chain = FilterChain::default().with_room_rules(&[
+            rule(words(&["secret"]), FilterAction::Mask),
+            rule(
+                RuleMatcher::Pattern {
+                    pattern: r"secret\s+plan".to_string(),
+                },
+                FilterAction::Reject,
+            ),
+        ]);
+        assert_eq!(
+            chain.screen("the secret plan").action,
+            Some(FilterAction::Reject)
+        );
+    }
+
+    #[test]
+    fn finds_links_with_and_without_a_scheme() {
+        let chain = FilterChain::default()
+            .with_room_rules(&[rule(RuleMatcher::Links, FilterAction::Reject)]);
+        assert!(chain.screen("see https://a.example/x").action.is_some());
+        assert!(chain.screen("go to www.example").action.is_some());
+        assert!(chain.screen("visit example.com").action.is_some());
+        assert!(chain.screen("no links here.").action.is_none());
+    }
+
+    #[test]
+    fn bad_patterns_do_not_compile() {
+        let bad = rule(
+            RuleMatcher::Pattern {
+                pattern: "(unclosed".to_string(),
+            },
+            FilterAction::Mask,
+        );
+        assert!(bad.compile().is_err());
+        // Skipped rather than breaking the rest of the chain
+        let chain = FilterChain::default()
+            .with_room_rules(&[bad, rule(words(&["darn"]), FilterAction::Mask)]);
+        assert_eq!(chain.screen("darn").content, "****");
+    }
+}
@@ -1,29 +1,314 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
-use mongodb::bson::Uuid;
+use mongodb::bson::{DateTime, Uuid};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    #[default]
+    Chat,
+    // Sent through /me, rendered as "* sender content"
+    Action,
+    // Generated by the server itself (topic changes, command replies, ...)
+    System,
+}
+
+// A previous version of a message, kept whenever it gets edited
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageEdit {
+    content: String,
+    edited_by: String,
+    // Milliseconds since the unix epoch
+    edited_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     id: Uuid,
+    // Position in the room, handed out by the room as the message is broadcast. Zero for
+    // anything stored before rooms numbered their messages
+    #[serde(default)]
+    seq: i64,
+    // Milliseconds since the unix epoch. Zero for anything stored before messages kept the time
+    #[serde(default)]
+    sent_at: i64,
     #[serde(with = "arc_string_serde")]
     pub sender: Arc<String>,
+    // Account that sent the message. Empty for server messages and anything stored before
+    // authors were tracked, which nobody but a moderator can then change
+    #[serde(default)]
+    author: String,
     #[serde(with = "arc_string_serde")]
     room_id: Arc<String>,
     content: String,
+    // Older documents in the store were written before kinds existed
+    #[serde(default)]
+    kind: MessageKind,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    edits: Vec<MessageEdit>,
+    // Deleted messages stay around as tombstones with their content wiped
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_by: Option<String>,
+    // Emoji to the accounts that reacted with it
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    reactions: BTreeMap<String, Vec<String>>,
+    // Id of the message that started the thread this is a reply in. Replies to replies still
+    // point at the start of the thread so threads stay one level deep
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<Uuid>,
+    // Only kept on the message starting a thread
+    #[serde(default, skip_serializing_if = "is_zero")]
+    reply_count: u32,
+    // Id the sending client gave the message, used to spot the same message being sent twice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    // Sent by a service account or one of the server's bots rather than a person
+    #[serde(default, skip_serializing_if = "is_false")]
+    bot: bool,
+    // Accounts picked out of @mentions in the content
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mentions: Vec<String>,
+}
+
+/*
+ * Every distinct name written as @name, where a name is made of letters, numbers, '-' and '_'
+ */
+pub fn parse_mentions(content: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = Vec::new();
+    for word in content.split_whitespace() {
+        let Some(name) = word.strip_prefix('@') else {
+            continue;
+        };
+        let name: String = name
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+            .collect();
+        if !name.is_empty() && !mentions.contains(&name) {
+            mentions.push(name);
+        }
+    }
+    mentions
+}
+
+fn is_zero(count: &u32) -> bool {
+    *count == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
 impl Message {
     pub fn new(id: Uuid, sender: Arc<String>, content: String, room_id: Arc<String>) -> Message {
+        Message::with_kind(id, sender, content, room_id, MessageKind::Chat)
+    }
+
+    pub fn with_kind(
+        id: Uuid,
+        sender: Arc<String>,
+        content: String,
+        room_id: Arc<String>,
+        kind: MessageKind,
+    ) -> Message {
+        // Server messages only ever mention names in passing
+        let mentions = match kind {
+            MessageKind::System => Vec::new(),
+            _ => parse_mentions(&content),
+        };
         Message {
             id,
+            seq: 0,
+            sent_at: DateTime::now().timestamp_millis(),
             sender,
+            author: String::new(),
             room_id,
             content,
+            kind,
+            edits: Vec::new(),
+            deleted: false,
+            deleted_by: None,
+            reactions: BTreeMap::new(),
+            reply_to: None,
+            reply_count: 0,
+            client_id: None,
+            bot: false,
+            mentions,
+        }
+    }
+
+    pub fn with_author(mut self, author: &str) -> Message {
+        self.author = author.to_string();
+        self
+    }
+
+    pub fn with_client_id(mut self, client_id: Option<String>) -> Message {
+        self.client_id = client_id;
+        self
+    }
+
+    // Only for messages that were never sent, edits go through edited
+    pub fn with_content(mut self, content: String) -> Message {
+        if self.kind != MessageKind::System {
+            self.mentions = parse_mentions(&content);
+        }
+        self.content = content;
+        self
+    }
+
+    pub fn with_bot(mut self, bot: bool) -> Message {
+        self.bot = bot;
+        self
+    }
+
+    pub fn sent_at(&self) -> i64 {
+        self.sent_at
+    }
+
+    pub fn room_id(&self) -> &str {
+        &self.room_id
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+
+    pub fn mentions(&self) -> &[String] {
+        &self.mentions
+    }
+
+    pub fn in_thread(mut self, root: Uuid) -> Message {
+        self.reply_to = Some(root);
+        self
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn seq(&self) -> i64 {
+        self.seq
+    }
+
+    pub fn set_seq(&mut self, seq: i64) {
+        self.seq = seq;
+    }
+
+    pub fn reply_to(&self) -> Option<Uuid> {
+        self.reply_to
+    }
+
+    pub fn reply_count(&self) -> u32 {
+        self.reply_count
+    }
+
+    /*
+     * Copy of the thread starting message counting one more reply
+     */
+    pub fn replied(&self) -> Message {
+        let mut replied = self.clone();
+        replied.reply_count += 1;
+        replied
+    }
+
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    pub fn is_edited(&self) -> bool {
+        !self.edits.is_empty()
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /*
+     * Copy of the message with new content, the current content is pushed onto the edit history
+     */
+    pub fn edited(&self, content: String, edited_by: &str) -> Message {
+        let mut edited = self.clone();
+        edited.edits.push(MessageEdit {
+            content: std::mem::replace(&mut edited.content, content),
+            edited_by: edited_by.to_string(),
+            edited_at: DateTime::now().timestamp_millis(),
+        });
+        edited.mentions = parse_mentions(&edited.content);
+        edited
+    }
+
+    /*
+     * Copy of the message with everything that was said wiped, including the edit history
+     */
+    pub fn tombstone(&self, deleted_by: &str) -> Message {
+        let mut tombstone = self.clone();
+        tombstone.content.clear();
+        tombstone.edits.clear();
+        tombstone.deleted = true;
+        tombstone.deleted_by = Some(deleted_by.to_string());
+        tombstone.reactions.clear();
+        tombstone.mentions.clear();
+        tombstone
+    }
+
+    /*
+     * Copy of the message that only tells where it was sent and by whom, for people who are
+     * not allowed to read it
+     */
+    pub fn redacted(&self) -> Message {
+        let mut redacted = self.clone();
+        redacted.content.clear();
+        redacted.edits.clear();
+        redacted.reactions.clear();
+        redacted.mentions.clear();
+        redacted
+    }
+
+    /*
+     * Copy of the message with the account's reaction toggled, along with whether it was added
+     */
+    pub fn toggle_reaction(&self, emoji: &str, account: &str) -> (Message, bool) {
+        let mut reacted = self.clone();
+        let accounts = reacted.reactions.entry(emoji.to_string()).or_default();
+        let added = match accounts.iter().position(|a| a == account) {
+            Some(indx) => {
+                accounts.remove(indx);
+                false
+            }
+            None => {
+                accounts.push(account.to_string());
+                true
+            }
+        };
+        if accounts.is_empty() {
+            reacted.reactions.remove(emoji);
         }
+        (reacted, added)
+    }
+
+    pub fn system(content: String, room_id: Arc<String>) -> Message {
+        Message::with_kind(
+            Uuid::new(),
+            Arc::new(SYSTEM_SENDER.to_string()),
+            content,
+            room_id,
+            MessageKind::System,
+        )
     }
 }
 
+pub const SYSTEM_SENDER: &str = "server";
+
 mod arc_string_serde {
     use serde::{Deserialize, Deserializer, Serializer};
     use std::sync::Arc;
@@ -43,3 +328,56 @@ mod arc_string_serde {
         Ok(Arc::new(s))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_are_picked_out_once() {
+        assert_eq!(
+            parse_mentions("@alice hi @bob_2, @alice again and @carol-x!"),
+            vec!["alice", "bob_2", "carol-x"]
+        );
+    }
+
+    #[test]
+    fn only_words_starting_with_at_mention() {
+        assert!(parse_mentions("mail me at alice@example.com").is_empty());
+        assert!(parse_mentions("a lone @ sign").is_empty());
+        assert!(parse_mentions("").is_empty());
+    }
+
+    #[test]
+    fn system_messages_mention_nobody() {
+        let room_id = Arc::new("lobby".to_string());
+        let system = Message::system("@alice joined".to_string(), Arc::clone(&room_id));
+        assert!(system.mentions().is_empty());
+
+        let chat = Message::new(
+            Uuid::new(),
+            Arc::new("bob".to_string()),
+            "hi @alice".to_string(),
+            room_id,
+        );
+        assert_eq!(chat.mentions(), ["alice"]);
+    }
+
+    #[test]
+    fn redacted_messages_keep_only_where_and_who() {
+        let msg = Message::new(
+            Uuid::new(),
+            Arc::new("bob".to_string()),
+            "secret plans @alice".to_string(),
+            Arc::new("lobby".to_string()),
+        )
+        .edited("new secret plans @alice".to_string(), "bob");
+        let redacted = msg.redacted();
+        assert_eq!(redacted.id(), msg.id());
+        assert_eq!(redacted.room_id(), "lobby");
+        assert_eq!(redacted.sender.as_str(), "bob");
+        assert!(redacted.content().is_empty());
+        assert!(redacted.edits.is_empty());
+        assert!(redacted.mentions().is_empty());
+    }
+}
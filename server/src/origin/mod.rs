@@ -0,0 +1,74 @@
+use std::collections::HashSet;
+
+use actix_cors::Cors;
+use actix_web::{HttpRequest, HttpResponse, http::header};
+
+// Origins from the ALLOWED_ORIGINS variable that browsers may call the server from, besides
+// the server itself. "*" lets any site in.
+#[derive(Debug, Clone)]
+pub struct OriginPolicy {
+    // Every origin is allowed when not set
+    allowed: Option<HashSet<String>>,
+}
+
+impl OriginPolicy {
+    pub fn from_env() -> OriginPolicy {
+        let origins = std::env::var("ALLOWED_ORIGINS").unwrap_or_default();
+        if origins.trim() == "*" {
+            println!("ALLOWED_ORIGINS is *, any website can connect");
+            return OriginPolicy { allowed: None };
+        }
+        let allowed: HashSet<String> = origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        OriginPolicy {
+            allowed: Some(allowed),
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.allowed
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(origin))
+    }
+
+    /*
+     * CORS headers for the HTTP endpoints. Nothing is added for the server's own pages, which
+     * are not cross origin.
+     */
+    pub fn cors(&self) -> Cors {
+        let policy = self.clone();
+        Cors::default()
+            .allowed_origin_fn(move |origin, _req| {
+                origin.to_str().is_ok_and(|origin| policy.allows(origin))
+            })
+            .allowed_methods(["GET", "POST", "DELETE"])
+            .allowed_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+            .max_age(3600)
+    }
+
+    /*
+     * Browsers send the page's origin when opening a websocket but do not apply CORS to it, so
+     * it is checked here. Clients outside a browser send none and are let through, as are
+     * pages served by this server.
+     */
+    pub fn check(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(origin) = req.headers().get(header::ORIGIN) else {
+            return Ok(());
+        };
+        let Ok(origin) = origin.to_str() else {
+            return Err(HttpResponse::Forbidden().body("Origin not allowed"));
+        };
+        let origin_host = origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"))
+            .unwrap_or(origin);
+        if origin_host == req.connection_info().host() || self.allows(origin) {
+            return Ok(());
+        }
+        println!("Refusing websocket from origin {origin}");
+        Err(HttpResponse::Forbidden().body("Origin not allowed"))
+    }
+}
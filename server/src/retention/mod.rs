@@ -0,0 +1,96 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database,
+    bson::{DateTime, Document, doc},
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    Err, RoomMap,
+    roomwebserver::server::Room,
+    settings::{RoomSettings, SETTINGS_COLLECTION},
+};
+
+// How often the retention settings are enforced against the store
+pub const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/*
+ * Removes the stored messages of every room past its max age or max count, then drops the
+ * same messages from the rooms that are open. Rooms on a legal hold are left alone.
+ */
+pub async fn enforce(database: &Database, rooms: &RoomMap) -> Result<u64, Err> {
+    let now = DateTime::now().timestamp_millis();
+    let collection: Collection<RoomSettings> = database.collection(SETTINGS_COLLECTION);
+    let filter = doc! {
+        "legal_hold": {"$ne": true},
+        "$or": [
+            {"retention_days": {"$ne": null}},
+            {"retention_messages": {"$ne": null}},
+        ],
+    };
+    let with_retention: Vec<RoomSettings> = collection.find(filter).await?.try_collect().await?;
+
+    let mut removed = 0;
+    for settings in with_retention.iter() {
+        match enforce_room(database, settings, now).await {
+            Ok(count) => removed += count,
+            Err(e) => println!("Unable to apply retention to {} {e:?}", settings.room_id),
+        }
+    }
+
+    let open_rooms: Vec<Arc<Mutex<Room>>> = rooms.lock().await.values().cloned().collect();
+    for room in open_rooms {
+        let mut room = room.lock().await;
+        let dropped = room.apply_retention(now);
+        if dropped > 0 {
+            println!("Dropped {dropped} expired messages from {}", room.room_id());
+        }
+    }
+    Ok(removed)
+}
+
+async fn enforce_room(database: &Database, settings: &RoomSettings, now: i64) -> Result<u64, Err> {
+    let collection: Collection<Document> = database.collection("messages");
+    let mut removed = 0;
+
+    // Messages stored before they kept the time count as old enough to go
+    if let Some(cutoff) = settings.retention_cutoff(now) {
+        let result = collection
+            .delete_many(doc! {"room_id": &settings.room_id, "sent_at": {"$lt": cutoff}})
+            .await?;
+        removed += result.deleted_count;
+    }
+
+    // The newest message past the limit, it and everything before it goes
+    if let Some(max) = settings.retention_limit() {
+        let oldest_removed = collection
+            .find_one(doc! {"room_id": &settings.room_id})
+            .sort(doc! {"seq": -1, "_id": -1})
+            .skip(max as u64)
+            .await?;
+        if let Some(oldest_removed) = oldest_removed {
+            let seq = oldest_removed.get_i64("seq").unwrap_or_default();
+            let id = oldest_removed.get_object_id("_id")?;
+            let result = collection
+                .delete_many(doc! {
+                    "room_id": &settings.room_id,
+                    "$or": [
+                        {"seq": {"$lt": seq}},
+                        {"seq": seq, "_id": {"$lte": id}},
+                    ],
+                })
+                .await?;
+            removed += result.deleted_count;
+        }
+    }
+
+    if removed > 0 {
+        println!(
+            "Removed {removed} messages from {} past retention",
+            settings.room_id
+        );
+    }
+    Ok(removed)
+}
@@ -1,29 +1,81 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use actix_web::{
     HttpRequest, HttpResponse,
     web::{self, Payload, Query},
 };
 
+use actix_ws::{CloseCode, CloseReason};
 use futures_util::TryStreamExt;
-use mongodb::{Collection, Database, bson::doc};
+use mongodb::{
+    Collection, Database,
+    bson::{Bson, Uuid, doc},
+};
 use tokio::sync::{Mutex, mpsc};
 
 use crate::{
-    Err, RoomMap, UserMap, dto::RoomInfoDTO, message::Message, roomwebserver::server::Room,
+    Err, RoomMap, UserMap, account,
+    admin::AdminAuth,
+    apitoken::{ApiCaller, ApiTokens},
+    direct,
+    dto::{
+        CreateInviteDTO, DirectoryQueryDTO, ExportQueryDTO, MessagesQueryDTO, PostMessageDTO,
+        PostedMessageDTO, ReadQueryDTO, RoomDirectoryEntryDTO, RoomInfoDTO, SearchQueryDTO,
+    },
+    event::ServerEvent,
+    export::{self, ExportFormat},
+    message::Message,
+    origin::OriginPolicy,
+    roomwebserver::server::{MAX_BUFFERED_MESSAGES, Room, RoomEvent, RoomServices},
+    search::{self, DEFAULT_SEARCH_LIMIT, MAX_SEARCH_LIMIT, SearchFilter, SearchScope},
+    serviceaccount,
+    settings::{self, Credentials, DEFAULT_INVITE_EXPIRY, Refusal, RoomSettings},
     user::User,
 };
 
+// Room ids end up as keys in stored documents, so they are kept to a safe set of characters
+pub const MAX_ROOM_ID_LENGTH: usize = 64;
+pub const DEFAULT_MESSAGES_LIMIT: usize = 50;
+pub const MAX_MESSAGES_LIMIT: usize = 200;
+
+pub fn is_valid_room_id(room_id: &str) -> bool {
+    !room_id.is_empty()
+        && room_id.len() <= MAX_ROOM_ID_LENGTH
+        && room_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 // This function is to establish the connection between the client and the server room
 // that is being attempted to join
+// Every argument is an extractor actix fills in
+#[allow(clippy::too_many_arguments)]
 pub async fn join_room(
     req: HttpRequest,
     stream: Payload,
     details: Query<RoomInfoDTO>,
     rooms: web::Data<RoomMap>,
     users: web::Data<UserMap>,
-    room_collection: web::Data<Database>,
+    services: web::Data<RoomServices>,
+    origins: web::Data<OriginPolicy>,
+    api_tokens: web::Data<ApiTokens>,
 ) -> Result<HttpResponse, Err> {
+    if let Err(res) = origins.check(&req) {
+        return Ok(res);
+    }
+    let room_collection = &services.database;
+    if !is_valid_room_id(&details.room_id) {
+        return Ok(HttpResponse::BadRequest().body(
+            "Room ids can only contain letters, numbers, '-' and '_' and be at most 64 characters",
+        ));
+    }
+    let signed_in = match api_tokens
+        .sign_in(details.token.as_deref(), room_collection)
+        .await
+    {
+        Ok(signed_in) => signed_in,
+        Err(res) => return Ok(res),
+    };
     let (res, session, receive_session) = match actix_ws::handle(&req, stream) {
         Ok(tuple) => tuple,
         Err(e) => {
@@ -34,26 +86,100 @@ pub async fn join_room(
 
     println!("Server side connection successgul!");
 
-    let mut guard_room = rooms.lock().await;
-    println!("Able to claim room lock");
-    let mut guard_user_room = users.lock().await;
-    println!("Able to claim the user room");
+    let uuid = Uuid::new();
+    let (user_tx, user_rx) = mpsc::channel::<ServerEvent>(32);
+    let pending_tx = user_tx.clone();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let user = User::new(
+        uuid,
+        details.username.to_owned(),
+        Arc::new(details.room_id.to_owned()),
+        user_tx,
+        shutdown_tx,
+    );
+    let user = match signed_in {
+        Some(account) => user.signed_in_as(account),
+        None => user,
+    };
+    let account = Arc::clone(&user.account);
+    let user = Arc::new(Mutex::new(user));
+    let room = match enter_room(
+        &rooms,
+        &users,
+        &services,
+        &details.room_id,
+        Arc::clone(&user),
+        &Credentials {
+            password: details.password.clone(),
+            invite: details.invite.clone(),
+        },
+    )
+    .await
+    {
+        Ok(room) => room,
+        Err(refusal) => {
+            println!("Refusing {account} entry to {}: {refusal}", details.room_id);
+            session
+                .close(Some(CloseReason {
+                    code: refusal_close_code(&refusal),
+                    description: Some(refusal.to_string()),
+                }))
+                .await
+                .unwrap_or_else(|e| println!("Unable to close refused session {e:?}"));
+            return Ok(res);
+        }
+    };
+    User::spawn_user_threads(
+        user,
+        session,
+        receive_session,
+        user_rx,
+        shutdown_rx,
+        Arc::downgrade(&room),
+    )
+    .await;
+    println!("Successfully added user to room!");
+
+    // Now that the user can be found by the router, hand over whatever arrived while they were away
+    direct::deliver_pending(room_collection, &account, &pending_tx)
+        .await
+        .unwrap_or_else(|e| println!("Unable to deliver pending direct messages {e:?}"));
 
-    let room_id = Arc::new(details.room_id.to_owned());
-    let room = match guard_room.get(&details.room_id) {
+    Ok(res)
+}
+
+/*
+ * The room if it is open, otherwise a new one loaded with its stored messages and settings
+ * along with true. Opened rooms are not in the map yet, callers insert them once they are
+ * sure the room is wanted.
+ */
+pub async fn find_or_open_room(
+    guard_room: &HashMap<String, Arc<Mutex<Room>>>,
+    services: &RoomServices,
+    room_id: &str,
+) -> Result<(Arc<Mutex<Room>>, bool), Refusal> {
+    let (room, opened) = match guard_room.get(room_id) {
         Some(room) => {
-            println!("Taking room of {:?}", details.room_id);
-            Arc::clone(room)
+            println!("Taking room of {room_id:?}");
+            (Arc::clone(room), false)
         }
         None => {
             println!("Opening room");
-            guard_user_room.insert(details.room_id.to_owned(), Vec::new());
+            let room_settings = match settings::find(&services.database, room_id).await {
+                Ok(room_settings) => room_settings,
+                Err(e) => {
+                    println!("Unable to read room settings {e:?}");
+                    return Err(Refusal::Unavailable);
+                }
+            };
 
-            // Retrieve the message from the db
-            let room_messages: Collection<Message> = room_collection.collection("messages");
-            let arg_format = doc! {"room_id": &details.room_id};
-            let room_messages: Vec<Arc<Message>> = room_messages
+            // Retrieve the newest messages from the db, older ones stay in the store
+            let room_messages: Collection<Message> = services.database.collection("messages");
+            let arg_format = doc! {"room_id": room_id};
+            let mut room_messages: Vec<Arc<Message>> = room_messages
                 .find(arg_format)
+                .sort(doc! {"seq": -1, "_id": -1})
+                .limit(MAX_BUFFERED_MESSAGES as i64)
                 .await
                 .unwrap()
                 .try_collect::<Vec<Message>>()
@@ -65,56 +191,413 @@ pub async fn join_room(
                 .into_iter()
                 .map(Arc::new)
                 .collect();
+            room_messages.reverse();
             println!("Messages: {room_messages:?}");
-            let (room, room_rx) =
-                Room::spawn_room(Arc::clone(&room_id), room_messages, room_collection.clone());
+            let (room, room_rx) = Room::spawn_room(
+                Arc::new(room_id.to_owned()),
+                room_messages,
+                room_settings,
+                services.clone(),
+            );
             let room = Arc::new(Mutex::new(room));
             let weak_room = Arc::downgrade(&room);
             tokio::spawn(async move { Room::run(weak_room, room_rx).await });
-            room
+            (room, true)
         }
     };
-    guard_room.insert(details.room_id.clone(), Arc::clone(&room));
-    drop(guard_room);
+    Ok((room, opened))
+}
+
+/*
+ * Finds the room, opening it with its stored messages and settings when nobody is in it, and
+ * adds the user to it and to the user map if the settings let them in
+ */
+pub async fn enter_room(
+    rooms: &RoomMap,
+    users: &UserMap,
+    services: &RoomServices,
+    room_id: &str,
+    user: Arc<Mutex<User>>,
+    credentials: &Credentials,
+) -> Result<Arc<Mutex<Room>>, Refusal> {
+    let (account, signed_in) = {
+        let user = user.lock().await;
+        (Arc::clone(&user.account), user.signed_in)
+    };
+    if !signed_in && services.moderators.contains(account.as_str()) {
+        return Err(Refusal::Moderator);
+    }
+    // Service accounts only sign in with their token, nobody gets to chat under their name
+    match serviceaccount::exists(&services.database, &account).await {
+        Ok(false) => {}
+        Ok(true) => return Err(Refusal::ServiceAccount),
+        Err(e) => {
+            println!("Unable to look up service accounts {e:?}");
+            return Err(Refusal::Unavailable);
+        }
+    }
+
+    let mut guard_room = rooms.lock().await;
+    println!("Able to claim room lock");
+    let mut guard_user_room = users.lock().await;
+    println!("Able to claim the user room");
+
+    let (room, opened) = find_or_open_room(&guard_room, services, room_id).await?;
 
     println!("Attempting to claim borrow_room");
     let mut borrow_room = room.lock().await;
     println!("Able to claim the borrow room lock");
-    let uuid = rand::random();
-    let (user_tx, user_rx) = mpsc::channel::<Arc<Message>>(32);
-    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
-    let user = User::new(
-        uuid,
-        details.username.to_owned(),
-        room_id,
-        user_tx,
-        shutdown_tx,
-    );
-    let user = Arc::new(Mutex::new(user));
+    let mut guard_user = user.lock().await;
+    // A room opened just for this join is dropped again if they are turned away
+    borrow_room.admit(&guard_user.account, guard_user.signed_in, credentials)?;
+    guard_user.username = borrow_room.claim_name(&guard_user.account, &guard_user.username)?;
+    drop(guard_user);
+    if opened {
+        guard_room.insert(room_id.to_owned(), Arc::clone(&room));
+    }
+    drop(guard_room);
     borrow_room.add_user(Arc::clone(&user)).await;
     println!("Attempting to drop borrow room");
     drop(borrow_room);
     println!("Dropped borrow room");
-    User::spawn_user_threads(
-        Arc::clone(&user),
-        session,
-        receive_session,
-        user_rx,
-        shutdown_rx,
-        Arc::downgrade(&room),
-    )
-    .await;
 
-    // Theoretically there should always be a user in this case.
     guard_user_room
-        .get_mut(&details.room_id)
-        .unwrap()
+        .entry(room_id.to_owned())
+        .or_default()
         .push(user);
-
     drop(guard_user_room);
-    println!("Successfully added user to room!");
+    Ok(room)
+}
 
-    Ok(res)
+// Full rooms may have space later, anything else is down to who is asking
+fn refusal_close_code(refusal: &Refusal) -> CloseCode {
+    match refusal {
+        Refusal::Full(_) => CloseCode::Again,
+        Refusal::Unavailable => CloseCode::Error,
+        _ => CloseCode::Policy,
+    }
+}
+
+/*
+ * Changes the settings of the open room when there is one so they are not overwritten when it
+ * closes, and the stored settings otherwise. The room map stays locked so the room cannot open
+ * halfway through.
+ */
+async fn change_room_settings<T>(
+    rooms: &RoomMap,
+    services: &RoomServices,
+    room_id: &str,
+    account: &str,
+    change: impl FnOnce(&mut RoomSettings) -> T,
+) -> Result<T, HttpResponse> {
+    let guard_room = rooms.lock().await;
+    if let Some(room) = guard_room.get(room_id) {
+        let mut room = room.lock().await;
+        return room
+            .change_settings_as(account, change)
+            .map_err(|e| HttpResponse::Forbidden().body(e));
+    }
+
+    let mut room_settings = settings::find(&services.database, room_id)
+        .await
+        .map_err(|e| {
+            println!("Unable to read room settings {e:?}");
+            HttpResponse::InternalServerError().body("Unable to read room settings")
+        })?;
+    let is_moderator = services.moderators.contains(account);
+    let changed = room_settings
+        .change_as(is_moderator, change)
+        .map_err(|e| HttpResponse::Forbidden().body(e))?;
+    settings::save(&services.database, &room_settings)
+        .await
+        .map_err(|e| {
+            println!("Unable to store room settings {e:?}");
+            HttpResponse::InternalServerError().body("Unable to store room settings")
+        })?;
+    drop(guard_room);
+    Ok(changed)
+}
+
+/*
+ * Creates an invite as the account of the API token, which has to be a moderator
+ */
+pub async fn create_invite(
+    req: HttpRequest,
+    path: web::Path<String>,
+    details: web::Json<CreateInviteDTO>,
+    rooms: web::Data<RoomMap>,
+    services: web::Data<RoomServices>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let caller = match api_tokens.check(&req, &services.database).await {
+        Ok(caller) => caller,
+        Err(res) => return res,
+    };
+    let room_id = path.into_inner();
+    if !is_valid_room_id(&room_id) {
+        return HttpResponse::BadRequest().body("Invalid room id");
+    }
+    if details.max_uses == Some(0) || details.expires_in_secs == Some(0) {
+        return HttpResponse::BadRequest().body("Invites need at least one use and some time");
+    }
+    let expires_in = details
+        .expires_in_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INVITE_EXPIRY);
+    match change_room_settings(&rooms, &services, &room_id, &caller.account, |settings| {
+        settings.create_invite(&caller.account, expires_in, details.max_uses)
+    })
+    .await
+    {
+        Ok(invite) => HttpResponse::Created().json(invite),
+        Err(res) => res,
+    }
+}
+
+/*
+ * Settings of the open room, which may not have been stored yet, or else the stored ones
+ */
+async fn current_settings(
+    rooms: &RoomMap,
+    database: &Database,
+    room_id: &str,
+) -> Result<RoomSettings, HttpResponse> {
+    let open_room = rooms.lock().await.get(room_id).cloned();
+    match open_room {
+        Some(room) => Ok(room.lock().await.settings().clone()),
+        None => settings::find(database, room_id).await.map_err(|e| {
+            println!("Unable to read room settings {e:?}");
+            HttpResponse::InternalServerError().body("Unable to read room settings")
+        }),
+    }
+}
+
+// Who is reading a room's history through the API
+enum Reader {
+    Admin,
+    Caller(ApiCaller),
+}
+
+/*
+ * The admin token reads everything, anyone else needs an API token. Readers are never taken
+ * from the query, anybody can write any name there.
+ */
+async fn reader(
+    req: &HttpRequest,
+    admin: &AdminAuth,
+    api_tokens: &ApiTokens,
+    database: &Database,
+) -> Result<Reader, HttpResponse> {
+    if admin.check(req).is_ok() {
+        return Ok(Reader::Admin);
+    }
+    api_tokens.check(req, database).await.map(Reader::Caller)
+}
+
+/*
+ * Whether the reader can look through the history of the room, with the password for
+ * password protected rooms. Moderators read everything.
+ */
+async fn check_read(
+    rooms: &RoomMap,
+    services: &RoomServices,
+    room_id: &str,
+    reader: &Reader,
+    password: Option<&str>,
+) -> Result<(), HttpResponse> {
+    let caller = match reader {
+        Reader::Admin => return Ok(()),
+        Reader::Caller(caller) => caller,
+    };
+    if !caller.can_read(room_id) {
+        return Err(HttpResponse::Forbidden().body("Token is not allowed to read this room"));
+    }
+    if services.moderators.contains(&caller.account) {
+        return Ok(());
+    }
+    let room_settings = current_settings(rooms, &services.database, room_id).await?;
+    match room_settings.can_read(Some(&caller.account), password) {
+        true => Ok(()),
+        false => Err(HttpResponse::Forbidden().body("Not allowed to read this room")),
+    }
+}
+
+/*
+ * Invites of a room that can still be used, only shown to API tokens of moderators
+ */
+pub async fn get_invites(
+    req: HttpRequest,
+    path: web::Path<String>,
+    rooms: web::Data<RoomMap>,
+    services: web::Data<RoomServices>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let caller = match api_tokens.check(&req, &services.database).await {
+        Ok(caller) => caller,
+        Err(res) => return res,
+    };
+    let room_id = path.into_inner();
+    let room_settings = match current_settings(&rooms, &services.database, &room_id).await {
+        Ok(room_settings) => room_settings,
+        Err(res) => return res,
+    };
+    if !services.moderators.contains(&caller.account) {
+        return HttpResponse::Forbidden().body("Only moderators can see invites");
+    }
+    HttpResponse::Ok().json(room_settings.outstanding_invites())
+}
+
+pub async fn revoke_invite(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    rooms: web::Data<RoomMap>,
+    services: web::Data<RoomServices>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let caller = match api_tokens.check(&req, &services.database).await {
+        Ok(caller) => caller,
+        Err(res) => return res,
+    };
+    let (room_id, token) = path.into_inner();
+    match change_room_settings(&rooms, &services, &room_id, &caller.account, |settings| {
+        settings.revoke_invite(&token)
+    })
+    .await
+    {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("No such invite in this room"),
+        Err(res) => res,
+    }
+}
+
+/*
+ * Sends a message into the room as the account of the API token, the same way a connected
+ * user would. A room nobody is in is opened for it and closes again once it is stored. Text
+ * starting with / is posted as is rather than run as a command.
+ */
+pub async fn post_message(
+    req: HttpRequest,
+    path: web::Path<String>,
+    details: web::Json<PostMessageDTO>,
+    rooms: web::Data<RoomMap>,
+    services: web::Data<RoomServices>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let caller = match api_tokens.check(&req, &services.database).await {
+        Ok(caller) => caller,
+        Err(res) => return res,
+    };
+    let room_id = path.into_inner();
+    if !is_valid_room_id(&room_id) {
+        return HttpResponse::BadRequest().body("Invalid room id");
+    }
+    if !caller.can_post(&room_id) {
+        return HttpResponse::Forbidden().body("Token is not allowed to post to this room");
+    }
+    let content = details.content.trim();
+    if content.is_empty() {
+        return HttpResponse::BadRequest().body("Messages need some content");
+    }
+
+    let mut guard_room = rooms.lock().await;
+    let (room, opened) = match find_or_open_room(&guard_room, &services, &room_id).await {
+        Ok(found) => found,
+        Err(refusal) => return HttpResponse::ServiceUnavailable().body(refusal.to_string()),
+    };
+    if opened {
+        guard_room.insert(room_id.clone(), Arc::clone(&room));
+    }
+    let room_tx = room.lock().await.sender();
+    drop(guard_room);
+
+    let msg = Message::new(
+        Uuid::new(),
+        Arc::new(caller.account.clone()),
+        content.to_string(),
+        Arc::new(room_id.clone()),
+    )
+    .with_author(&caller.account)
+    .with_client_id(details.client_id.clone())
+    .with_bot(caller.is_bot());
+    let id = msg.id();
+    if let Err(e) = room_tx.send(RoomEvent::Message(Arc::new(msg))).await {
+        println!("Unable to post message into {room_id} {e:?}");
+        return HttpResponse::ServiceUnavailable().body("Room is closing, try again");
+    }
+    HttpResponse::Accepted().json(PostedMessageDTO {
+        id: id.to_string(),
+        room_id,
+    })
+}
+
+/*
+ * Messages of the room for API tokens allowed to read it, oldest first. With after, the ones
+ * following that sequence number, otherwise the latest. An open room answers from the messages
+ * it holds, which are the latest few hundred.
+ */
+pub async fn get_messages(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: Query<MessagesQueryDTO>,
+    rooms: web::Data<RoomMap>,
+    database: web::Data<Database>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let caller = match api_tokens.check(&req, &database).await {
+        Ok(caller) => caller,
+        Err(res) => return res,
+    };
+    let room_id = path.into_inner();
+    if !is_valid_room_id(&room_id) {
+        return HttpResponse::BadRequest().body("Invalid room id");
+    }
+    if !caller.can_read(&room_id) {
+        return HttpResponse::Forbidden().body("Token is not allowed to read this room");
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_MESSAGES_LIMIT)
+        .clamp(1, MAX_MESSAGES_LIMIT);
+
+    let room = rooms.lock().await.get(&room_id).cloned();
+    if let Some(room) = room {
+        let room = room.lock().await;
+        return HttpResponse::Ok().json(room.recent_messages(query.after, limit));
+    }
+
+    let collection: Collection<Message> = database.collection("messages");
+    let found = match query.after {
+        Some(after) => {
+            collection
+                .find(doc! {"room_id": &room_id, "seq": {"$gt": after}})
+                .sort(doc! {"seq": 1, "_id": 1})
+                .limit(limit as i64)
+                .await
+        }
+        None => {
+            collection
+                .find(doc! {"room_id": &room_id})
+                .sort(doc! {"seq": -1, "_id": -1})
+                .limit(limit as i64)
+                .await
+        }
+    };
+    let messages: Result<Vec<Message>, mongodb::error::Error> = match found {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match messages {
+        Ok(mut messages) => {
+            if query.after.is_none() {
+                messages.reverse();
+            }
+            HttpResponse::Ok().json(messages)
+        }
+        Err(e) => {
+            println!("Unable to read messages {e:?}");
+            HttpResponse::InternalServerError().body("Unable to read messages")
+        }
+    }
 }
 
 pub async fn get_user_connections(
@@ -127,3 +610,372 @@ pub async fn get_user_connections(
         "User connections: \n{users:?}\n Available rooms: \n {rooms:?}"
     ))
 }
+
+/*
+ * Conversation between two accounts, for an API token of the first one or the admin token
+ */
+pub async fn get_direct_history(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    database: web::Data<Database>,
+    api_tokens: web::Data<ApiTokens>,
+    admin: web::Data<AdminAuth>,
+) -> HttpResponse {
+    let (account, other) = path.into_inner();
+    if admin.check(&req).is_err() {
+        match api_tokens.check(&req, &database).await {
+            Ok(caller) if caller.account == account => {}
+            Ok(_) => {
+                return HttpResponse::Forbidden()
+                    .body("Token can only read direct messages of its own account");
+            }
+            Err(res) => return res,
+        }
+    }
+    match direct::history(&database, &account, &other).await {
+        Ok(messages) => HttpResponse::Ok().json(messages),
+        Err(e) => {
+            println!("Unable to read direct messages {e:?}");
+            HttpResponse::InternalServerError().body("Unable to read direct messages")
+        }
+    }
+}
+
+/*
+ * A message and its replies, for the admin token or API tokens that can read the room
+ */
+pub async fn get_thread(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: Query<ReadQueryDTO>,
+    rooms: web::Data<RoomMap>,
+    services: web::Data<RoomServices>,
+    admin: web::Data<AdminAuth>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let (room_id, message_id) = path.into_inner();
+    if !is_valid_room_id(&room_id) {
+        return HttpResponse::BadRequest().body("Invalid room id");
+    }
+    let Ok(root) = Uuid::parse_str(&message_id) else {
+        return HttpResponse::BadRequest().body("Invalid message id");
+    };
+    let reader = match reader(&req, &admin, &api_tokens, &services.database).await {
+        Ok(reader) => reader,
+        Err(res) => return res,
+    };
+    if let Err(res) = check_read(
+        &rooms,
+        &services,
+        &room_id,
+        &reader,
+        query.password.as_deref(),
+    )
+    .await
+    {
+        return res;
+    }
+
+    // An open room holds messages that have not been written to the store yet
+    let room = rooms.lock().await.get(&room_id).cloned();
+    if let Some(room) = room {
+        let room = room.lock().await;
+        return HttpResponse::Ok().json(room.thread_messages(root));
+    }
+
+    let collection: Collection<Message> = services.database.collection("messages");
+    let filter = doc! {
+        "room_id": &room_id,
+        "$or": [{"id": root}, {"reply_to": root}],
+    };
+    let thread: Result<Vec<Message>, mongodb::error::Error> = match collection.find(filter).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match thread {
+        Ok(thread) => HttpResponse::Ok().json(thread),
+        Err(e) => {
+            println!("Unable to read thread {e:?}");
+            HttpResponse::InternalServerError().body("Unable to read thread")
+        }
+    }
+}
+
+/*
+ * Transcript of the messages of a room in the store, streamed as it is read, for the admin
+ * token or API tokens that can read the room. Messages of an open room are only in it once
+ * the room has been flushed or closed.
+ */
+pub async fn export_room(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: Query<ExportQueryDTO>,
+    rooms: web::Data<RoomMap>,
+    services: web::Data<RoomServices>,
+    admin: web::Data<AdminAuth>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let room_id = path.into_inner();
+    if !is_valid_room_id(&room_id) {
+        return HttpResponse::BadRequest().body("Invalid room id");
+    }
+    let reader = match reader(&req, &admin, &api_tokens, &services.database).await {
+        Ok(reader) => reader,
+        Err(res) => return res,
+    };
+    if let Err(res) = check_read(
+        &rooms,
+        &services,
+        &room_id,
+        &reader,
+        query.password.as_deref(),
+    )
+    .await
+    {
+        return res;
+    }
+    let format = match query.format.as_deref() {
+        Some(format) => match ExportFormat::parse(format) {
+            Some(format) => format,
+            None => return HttpResponse::BadRequest().body("Format has to be json, csv or md"),
+        },
+        None => ExportFormat::Json,
+    };
+    let parse_bound = |bound: &Option<String>| bound.as_deref().map(export::parse_time).transpose();
+    let (from, to) = match (parse_bound(&query.from), parse_bound(&query.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let cursor = match export::find_messages(&services.database, &room_id, from, to).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            println!("Unable to read messages for export {e:?}");
+            return HttpResponse::InternalServerError().body("Unable to read messages");
+        }
+    };
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{room_id}.{}\"", format.extension()),
+        ))
+        .streaming(export::render(format, room_id, cursor))
+}
+
+/*
+ * Everything but the rooms to look through, shared by both searches
+ */
+fn search_filter(query: &SearchQueryDTO, scope: SearchScope) -> Result<SearchFilter, HttpResponse> {
+    if query.q.trim().is_empty() {
+        return Err(HttpResponse::BadRequest().body("Search needs something to look for"));
+    }
+    let parse_bound = |bound: &Option<String>| bound.as_deref().map(export::parse_time).transpose();
+    let (from, to) = match (parse_bound(&query.from), parse_bound(&query.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return Err(HttpResponse::BadRequest().body(e)),
+    };
+    Ok(SearchFilter {
+        query: query.q.trim().to_string(),
+        scope,
+        sender: query.sender.clone(),
+        from,
+        to,
+        limit: query
+            .limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .clamp(1, MAX_SEARCH_LIMIT),
+    })
+}
+
+async fn run_search(database: &Database, filter: SearchFilter) -> HttpResponse {
+    match search::search(database, &filter).await {
+        Ok(hits) => HttpResponse::Ok().json(hits),
+        Err(e) => {
+            println!("Unable to search messages {e:?}");
+            HttpResponse::InternalServerError().body("Unable to search messages")
+        }
+    }
+}
+
+/*
+ * Searches the stored history of one room, for the admin token or API tokens that can read it
+ */
+pub async fn search_room(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: Query<SearchQueryDTO>,
+    rooms: web::Data<RoomMap>,
+    services: web::Data<RoomServices>,
+    admin: web::Data<AdminAuth>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let room_id = path.into_inner();
+    if !is_valid_room_id(&room_id) {
+        return HttpResponse::BadRequest().body("Invalid room id");
+    }
+    let reader = match reader(&req, &admin, &api_tokens, &services.database).await {
+        Ok(reader) => reader,
+        Err(res) => return res,
+    };
+    if let Err(res) = check_read(
+        &rooms,
+        &services,
+        &room_id,
+        &reader,
+        query.password.as_deref(),
+    )
+    .await
+    {
+        return res;
+    }
+    match search_filter(&query, SearchScope::Room(room_id)) {
+        Ok(filter) => run_search(&services.database, filter).await,
+        Err(res) => res,
+    }
+}
+
+/*
+ * Searches every room the reader can read without a password. The admin token and moderators
+ * search everything, service accounts only the rooms they can read.
+ */
+pub async fn search_rooms(
+    req: HttpRequest,
+    query: Query<SearchQueryDTO>,
+    services: web::Data<RoomServices>,
+    admin: web::Data<AdminAuth>,
+    api_tokens: web::Data<ApiTokens>,
+) -> HttpResponse {
+    let caller = match reader(&req, &admin, &api_tokens, &services.database).await {
+        Ok(Reader::Admin) => None,
+        Ok(Reader::Caller(caller)) => Some(caller),
+        Err(res) => return res,
+    };
+    let restricted = match &caller {
+        Some(caller) if !services.moderators.contains(&caller.account) => {
+            match settings::restricted_for(&services.database, Some(&caller.account)).await {
+                Ok(restricted) => restricted,
+                Err(e) => {
+                    println!("Unable to read room settings {e:?}");
+                    return HttpResponse::InternalServerError()
+                        .body("Unable to read room settings");
+                }
+            }
+        }
+        _ => Vec::new(),
+    };
+    let scope = match caller.as_ref().and_then(ApiCaller::read_scope) {
+        Some(rooms) => SearchScope::Within {
+            rooms: rooms.to_vec(),
+            except: restricted,
+        },
+        None => SearchScope::Except(restricted),
+    };
+    match search_filter(&query, scope) {
+        Ok(filter) => run_search(&services.database, filter).await,
+        Err(res) => res,
+    }
+}
+
+/*
+ * Every room that is open or has messages in the store, with unread counts for the account
+ * asking when one is given
+ */
+pub async fn get_rooms(
+    query: Query<DirectoryQueryDTO>,
+    rooms: web::Data<RoomMap>,
+    database: web::Data<Database>,
+) -> HttpResponse {
+    let account = match &query.account {
+        Some(account) => match account::find(&database, account).await {
+            Ok(account) => Some(account),
+            Err(e) => {
+                println!("Unable to read account {e:?}");
+                return HttpResponse::InternalServerError().body("Unable to read account");
+            }
+        },
+        None => None,
+    };
+    let unread_for = |room: &Room, room_id: &str| match &account {
+        Some(account) => room.unread_count(&account.account, account.read_marker(room_id)),
+        None => 0,
+    };
+
+    let mut entries: Vec<RoomDirectoryEntryDTO> = Vec::new();
+    let open_rooms: Vec<(String, Arc<Mutex<Room>>)> = rooms
+        .lock()
+        .await
+        .iter()
+        .map(|(room_id, room)| (room_id.clone(), Arc::clone(room)))
+        .collect();
+    for (room_id, room) in open_rooms.iter() {
+        let room = room.lock().await;
+        entries.push(RoomDirectoryEntryDTO {
+            room_id: room_id.clone(),
+            open: true,
+            members: room.member_names().len(),
+            topic: room.topic().map(str::to_string),
+            latest_seq: room.latest_seq(),
+            unread: unread_for(&room, room_id),
+            read_only: room.settings().read_only,
+            slow_mode_secs: room.settings().slow_mode_secs,
+        });
+    }
+
+    let collection: Collection<Message> = database.collection("messages");
+    let stored_rooms = match collection.distinct("room_id", doc! {}).await {
+        Ok(stored_rooms) => stored_rooms,
+        Err(e) => {
+            println!("Unable to read stored rooms {e:?}");
+            return HttpResponse::InternalServerError().body("Unable to read rooms");
+        }
+    };
+    for room_id in stored_rooms {
+        let Bson::String(room_id) = room_id else {
+            continue;
+        };
+        if open_rooms.iter().any(|(open_id, _)| *open_id == room_id) {
+            continue;
+        }
+        // Rooms whose settings cannot be read are listed with the defaults
+        let room_settings = settings::find(&database, &room_id)
+            .await
+            .unwrap_or_else(|e| {
+                println!("Unable to read settings of {room_id} {e:?}");
+                RoomSettings::new(&room_id)
+            });
+        let latest_seq = collection
+            .find_one(doc! {"room_id": &room_id})
+            .sort(doc! {"seq": -1})
+            .await
+            .ok()
+            .flatten()
+            .map(|msg| msg.seq())
+            .unwrap_or_default()
+            .max(room_settings.last_seq);
+        let unread = match &account {
+            Some(account) => collection
+                .count_documents(doc! {
+                    "room_id": &room_id,
+                    "seq": {"$gt": account.read_marker(&room_id)},
+                    "author": {"$ne": &account.account},
+                })
+                .await
+                .unwrap_or_default() as usize,
+            None => 0,
+        };
+        entries.push(RoomDirectoryEntryDTO {
+            room_id,
+            open: false,
+            members: 0,
+            topic: None,
+            latest_seq,
+            unread,
+            read_only: room_settings.read_only,
+            slow_mode_secs: room_settings.slow_mode_secs,
+        });
+    }
+
+    entries.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+    HttpResponse::Ok().json(entries)
+}
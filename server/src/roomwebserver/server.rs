@@ -1,26 +1,125 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt,
     sync::{Arc, Weak},
+    time::{Duration, Instant},
 };
 
 use actix_web::web;
-use mongodb::{Collection, Database};
+use mongodb::{
+    Collection, Database,
+    bson::{Uuid, doc},
+};
 use tokio::sync::{
     self, Mutex,
     mpsc::{self, Receiver, Sender},
 };
 
-use crate::{Err, message::Message, user::User};
+use crate::{
+    Err, account,
+    bot::{Bot, BotAction, BotCommand, BotRegistry},
+    command::{CommandEffect, CommandRegistry},
+    direct::{DirectMessage, DirectRouter},
+    event::ServerEvent,
+    filter::{self, FilterAction, FilterChain, ModerationLogEntry},
+    message::{Message, MessageKind},
+    settings::{self, Credentials, DuplicateNames, Refusal, RoomSettings},
+    user::User,
+    webhook::{WebhookDispatcher, WebhookEvent},
+};
+
+// Everything a user can push into the room channel
+#[derive(Debug)]
+pub enum RoomEvent {
+    Message(Arc<Message>),
+    Command {
+        user_id: Uuid,
+        name: String,
+        args: String,
+    },
+    Typing {
+        user_id: Uuid,
+        active: bool,
+    },
+    Read {
+        user_id: Uuid,
+        seq: i64,
+    },
+    // Bot actions that were put off, account is who set the bot off
+    Bot {
+        bot: &'static str,
+        account: Option<Arc<String>>,
+        action: BotAction,
+    },
+}
+
+// How long someone shows up as typing without the client telling the room again
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+// How long a client message id is remembered for spotting the same message sent again
+pub const CLIENT_ID_WINDOW: Duration = Duration::from_secs(300);
+// Sent messages held before the room writes them out without waiting to close
+pub const MAX_UNSAVED_MESSAGES: usize = 100;
+// Stored messages an open room keeps around for new members and edits, the oldest go first
+pub const MAX_BUFFERED_MESSAGES: usize = 500;
+
+#[derive(Debug)]
+struct SentMessage {
+    received_at: Instant,
+    id: Uuid,
+    seq: i64,
+}
+
+// Server wide pieces every room needs a handle on
+#[derive(Debug, Clone)]
+pub struct RoomServices {
+    pub database: web::Data<Database>,
+    pub commands: web::Data<CommandRegistry>,
+    pub direct: web::Data<DirectRouter>,
+    pub webhooks: web::Data<WebhookDispatcher>,
+    pub bots: web::Data<BotRegistry>,
+    // Server wide content filters, rooms add their own rules to them
+    pub filters: web::Data<FilterChain>,
+    // Accounts that manage rooms and can edit and delete anyone's messages. Websockets only
+    // get to use these names once signed in with the account's API token.
+    pub moderators: Arc<HashSet<String>>,
+}
+
+#[derive(Debug)]
+pub struct Member {
+    pub account: Arc<String>,
+    pub username: Arc<String>,
+    // Signed in with an API token rather than only giving a name
+    signed_in: bool,
+    user: Arc<Mutex<User>>,
+    session_tx: mpsc::Sender<ServerEvent>,
+    shutdown_tx: sync::watch::Sender<bool>,
+}
 
 #[derive(Debug)]
 pub struct Room {
     room_id: Arc<String>,
     messages: Vec<Arc<Message>>,
     inital_messages: Vec<Arc<Message>>,
-    members: HashMap<u32, (mpsc::Sender<Arc<Message>>, sync::watch::Sender<bool>)>,
-    sender: Sender<Arc<Message>>,
-    database: web::Data<Database>,
+    // Stored messages that were edited or deleted since the room opened and need rewriting
+    changed_messages: HashSet<Uuid>,
+    members: HashMap<Uuid, Member>,
+    // Members currently typing and when the room last heard about it
+    typing: HashMap<Uuid, Instant>,
+    // Account to the last sequence number it has read in this room
+    read_markers: HashMap<String, i64>,
+    // Account to when it last posted, only kept while slow mode is on
+    last_posted: HashMap<String, Instant>,
+    // (account, client id) of recently received messages
+    recent_client_ids: HashMap<(String, String), SentMessage>,
+    next_seq: i64,
+    sender: Sender<RoomEvent>,
+    services: RoomServices,
+    // Bots following this room, picked when it opens
+    bots: Vec<Arc<dyn Bot>>,
+    // Server wide filters followed by the room's rules, rebuilt when the settings change
+    filters: Arc<FilterChain>,
+    topic: Option<String>,
+    settings: RoomSettings,
     pub is_closed: bool,
 }
 
@@ -28,30 +127,459 @@ impl Room {
     pub fn spawn_room(
         room_id: Arc<String>,
         inital_messages: Vec<Arc<Message>>,
-        database: web::Data<Database>,
-    ) -> (Room, Receiver<Arc<Message>>) {
-        let (room_tx, room_rx) = mpsc::channel::<Arc<Message>>(100);
+        settings: RoomSettings,
+        services: RoomServices,
+    ) -> (Room, Receiver<RoomEvent>) {
+        let (room_tx, room_rx) = mpsc::channel::<RoomEvent>(100);
+        let next_seq = inital_messages
+            .iter()
+            .map(|msg| msg.seq())
+            .chain([settings.last_seq])
+            .max()
+            .unwrap_or_default()
+            + 1;
+        let bots = services.bots.for_room(&room_id);
+        let filters = Arc::new(services.filters.with_room_rules(&settings.automod));
         let room = Room {
             room_id,
             inital_messages,
             messages: Vec::new(),
+            changed_messages: HashSet::new(),
             members: HashMap::new(),
+            typing: HashMap::new(),
+            read_markers: HashMap::new(),
+            last_posted: HashMap::new(),
+            recent_client_ids: HashMap::new(),
+            next_seq,
             sender: room_tx,
-            database,
+            services,
+            bots,
+            filters,
+            topic: None,
+            settings,
             is_closed: false,
         };
 
         (room, room_rx)
     }
 
-    pub async fn add_user(&mut self, user: Arc<Mutex<User>>) {
+    pub fn room_id(&self) -> Arc<String> {
+        Arc::clone(&self.room_id)
+    }
+
+    pub fn commands(&self) -> &CommandRegistry {
+        &self.services.commands
+    }
+
+    pub fn webhooks(&self) -> &WebhookDispatcher {
+        &self.services.webhooks
+    }
+
+    // Commands answered by the room's bots rather than the registry
+    pub fn bot_commands(&self) -> impl Iterator<Item = &BotCommand> {
+        self.bots.iter().flat_map(|bot| bot.commands())
+    }
+
+    pub fn topic(&self) -> Option<&str> {
+        self.topic.as_deref()
+    }
+
+    pub fn set_topic(&mut self, topic: Option<String>) {
+        self.topic = topic;
+    }
+
+    pub fn settings(&self) -> &RoomSettings {
+        &self.settings
+    }
+
+    /*
+     * The account of the member if they are allowed to change the room settings
+     */
+    pub fn settings_manager(&self, user_id: Uuid) -> Result<Arc<String>, String> {
+        let account = self
+            .account(user_id)
+            .ok_or_else(|| "You are not a member of this room".to_string())?;
+        if !self.is_moderator_member(user_id) {
+            return Err(settings::NOT_MODERATOR.to_string());
+        }
+        Ok(account)
+    }
+
+    pub fn change_settings<T>(
+        &mut self,
+        user_id: Uuid,
+        change: impl FnOnce(&mut RoomSettings) -> T,
+    ) -> Result<T, String> {
+        let account = self.settings_manager(user_id)?;
+        self.change_settings_as(&account, change)
+    }
+
+    pub fn change_settings_as<T>(
+        &mut self,
+        account: &str,
+        change: impl FnOnce(&mut RoomSettings) -> T,
+    ) -> Result<T, String> {
+        let is_moderator = self.is_moderator(account);
+        let changed = self.settings.change_as(is_moderator, change)?;
+        self.filters = Arc::new(
+            self.services
+                .filters
+                .with_room_rules(&self.settings.automod),
+        );
+        self.save_settings();
+        Ok(changed)
+    }
+
+    fn save_settings(&self) {
+        let database = self.services.database.clone();
+        let settings = self.settings.clone();
+        tokio::spawn(async move {
+            settings::save(&database, &settings)
+                .await
+                .unwrap_or_else(|e| println!("Unable to store room settings {e:?}"));
+        });
+    }
+
+    /*
+     * Runs content an account is putting into the room through the content filters, logging
+     * anything they did. Hands back the content to use along with what to tell the account,
+     * or only what to tell them when it was rejected.
+     */
+    pub fn screen(
+        &self,
+        account: &str,
+        content: &str,
+        message_id: Option<Uuid>,
+    ) -> Result<(String, Option<String>), String> {
+        let screening = self.filters.screen(content);
+        let note = screening.note();
+        let rejected = screening.action == Some(FilterAction::Reject);
+        let logged_id = message_id.filter(|_| !rejected);
+        if let Some(entry) =
+            ModerationLogEntry::new(&self.room_id, account, content, logged_id, &screening)
+        {
+            filter::log(&self.services.database, entry);
+        }
+        match (rejected, note) {
+            (true, note) => Err(note.unwrap_or_default()),
+            (false, note) => Ok((screening.content, note)),
+        }
+    }
+
+    /*
+     * Screens a message before it goes out as sent by the account, handing back what should go
+     * out, if anything, and what to tell whoever sent it. System messages are made up by the
+     * server and pass as is.
+     */
+    fn screen_message(
+        &self,
+        account: &str,
+        msg: Arc<Message>,
+    ) -> (Option<Arc<Message>>, Option<String>) {
+        if msg.kind() == MessageKind::System {
+            return (Some(msg), None);
+        }
+        match self.screen(account, msg.content(), Some(msg.id())) {
+            Ok((content, note)) if content != msg.content() => (
+                Some(Arc::new(msg.as_ref().clone().with_content(content))),
+                note,
+            ),
+            Ok((_, note)) => (Some(msg), note),
+            Err(note) => (None, Some(note)),
+        }
+    }
+
+    /*
+     * Sends out a message from a member once it got past the filters and the posting rules
+     */
+    async fn post(&mut self, msg: Arc<Message>) -> Option<Arc<Message>> {
+        let account = msg.author().to_string();
+        let client_id = msg.client_id().map(str::to_string);
+        let (msg, note) = self.screen_message(&account, msg);
+        let admitted = match msg {
+            Some(msg) => self.admit_post(&msg).map(|_| msg),
+            None => Err(note.clone().unwrap_or_default()),
+        };
+        match admitted {
+            Ok(msg) => {
+                if let Some(note) = note {
+                    let note = Arc::new(Message::system(note, self.room_id()));
+                    self.send_to_account(&account, note).await;
+                }
+                Some(self.broadcast(msg).await)
+            }
+            Err(reason) => {
+                self.refuse(&account, client_id, reason).await;
+                None
+            }
+        }
+    }
+
+    /*
+     * Tells the sessions of the account why what it sent did not go out. Messages that came
+     * with a client id are rejected by that id, so the client can mark them as not sent rather
+     * than send them again.
+     */
+    async fn refuse(&self, account: &str, client_id: Option<String>, reason: String) {
+        println!(
+            "Refusing message from {account} in {}: {reason}",
+            self.room_id
+        );
+        let event = match client_id {
+            Some(client_id) => ServerEvent::Rejected { client_id, reason },
+            None => ServerEvent::Message(Arc::new(Message::system(reason, self.room_id()))),
+        };
+        for member in self.members.values().filter(|m| *m.account == account) {
+            member
+                .session_tx
+                .send(event.clone())
+                .await
+                .unwrap_or_else(|_| println!("Unable to tell {account} about a refused message"));
+        }
+    }
+
+    // Moderators are held to neither read only rooms nor slow mode
+    fn exempt_from_limits(&self, account: &str) -> bool {
+        self.is_moderator(account)
+    }
+
+    /*
+     * Whether the account can post right now, with the reason when it cannot
+     */
+    pub fn check_posting(&self, account: &str) -> Result<(), String> {
+        if self.exempt_from_limits(account) {
+            return Ok(());
+        }
+        self.settings
+            .check_posting(self.last_posted.get(account).map(Instant::elapsed))
+    }
+
+    /*
+     * Checks a message against the posting rules of its author just before it is broadcast.
+     * Announcements the server makes itself have no author and always go through.
+     */
+    fn admit_post(&mut self, msg: &Message) -> Result<(), String> {
+        match msg.author() {
+            "" => Ok(()),
+            account => self.admit_as(account),
+        }
+    }
+
+    /*
+     * Holds the account to the posting rules for something about to go out on its behalf,
+     * counting it towards slow mode when it is let through
+     */
+    fn admit_as(&mut self, account: &str) -> Result<(), String> {
+        self.check_posting(account)?;
+        if self.settings.slow_mode_secs.is_some() && !self.exempt_from_limits(account) {
+            self.last_posted.insert(account.to_string(), Instant::now());
+        }
+        Ok(())
+    }
+
+    fn room_mode(&self, account: &str) -> ServerEvent {
+        let exempt = self.exempt_from_limits(account);
+        ServerEvent::RoomMode {
+            can_post: exempt || !self.settings.read_only,
+            slow_mode_secs: self.settings.slow_mode_secs.filter(|_| !exempt),
+        }
+    }
+
+    async fn send_room_mode(&self) {
+        for member in self.members.values() {
+            member
+                .session_tx
+                .send(self.room_mode(&member.account))
+                .await
+                .unwrap_or_else(|_| println!("Unable to send room mode"));
+        }
+    }
+
+    // Checked before add_user, moderators who signed in can always get in
+    pub fn admit(
+        &mut self,
+        account: &str,
+        signed_in: bool,
+        credentials: &Credentials,
+    ) -> Result<(), Refusal> {
+        if signed_in && self.is_moderator(account) {
+            return Ok(());
+        }
+        let members = self.members.len();
+        self.settings.admit(account, credentials, members)?;
+        // Using an invite counts against it
+        if credentials.invite.is_some() {
+            self.save_settings();
+        }
+        Ok(())
+    }
+
+    /*
+     * The name someone joining ends up with. A name another account is using is either refused
+     * or gets a number added, depending on the room settings.
+     */
+    pub fn claim_name(&self, account: &str, name: &Arc<String>) -> Result<Arc<String>, Refusal> {
+        let taken = |candidate: &str| {
+            self.members.values().any(|member| {
+                member.account.as_str() != account && member.username.as_str() == candidate
+            })
+        };
+        if !taken(name) {
+            return Ok(Arc::clone(name));
+        }
+        match self.settings.duplicate_names {
+            DuplicateNames::Reject => Err(Refusal::NameTaken(name.to_string())),
+            DuplicateNames::Suffix => {
+                let mut suffix = 2;
+                while taken(&format!("{name}-{suffix}")) {
+                    suffix += 1;
+                }
+                Ok(Arc::new(format!("{name}-{suffix}")))
+            }
+        }
+    }
+
+    pub fn display_name(&self, user_id: Uuid) -> Option<Arc<String>> {
+        self.members
+            .get(&user_id)
+            .map(|member| Arc::clone(&member.username))
+    }
+
+    pub fn account(&self, user_id: Uuid) -> Option<Arc<String>> {
+        self.members
+            .get(&user_id)
+            .map(|member| Arc::clone(&member.account))
+    }
+
+    /*
+     * For accounts that were checked already, such as those of API tokens or of members who
+     * signed in. Members are checked with is_moderator_member.
+     */
+    pub fn is_moderator(&self, account: &str) -> bool {
+        self.services.moderators.contains(account)
+    }
+
+    // Whether the member signed in as a moderator account, giving its name is not enough
+    pub fn is_moderator_member(&self, user_id: Uuid) -> bool {
+        self.members
+            .get(&user_id)
+            .is_some_and(|member| member.signed_in && self.is_moderator(&member.account))
+    }
+
+    pub fn find_message(&self, id: Uuid) -> Option<Arc<Message>> {
+        self.messages
+            .iter()
+            .chain(self.inital_messages.iter())
+            .find(|msg| msg.id() == id)
+            .cloned()
+    }
+
+    /*
+     * Swaps out the message with the same id, used for edits and deletions
+     */
+    pub fn replace_message(&mut self, msg: Arc<Message>) {
+        if let Some(slot) = self.messages.iter_mut().find(|m| m.id() == msg.id()) {
+            *slot = msg;
+            return;
+        }
+        if let Some(slot) = self.inital_messages.iter_mut().find(|m| m.id() == msg.id()) {
+            self.changed_messages.insert(msg.id());
+            *slot = msg;
+        }
+    }
+
+    /*
+     * Counts a reply that went out towards its thread and tells the members
+     */
+    async fn count_reply(&mut self, root: Uuid) {
+        let Some(root) = self.find_message(root) else {
+            return;
+        };
+        let replied = Arc::new(root.replied());
+        self.replace_message(Arc::clone(&replied));
+        self.notify(ServerEvent::ThreadUpdated {
+            message_id: replied.id(),
+            reply_count: replied.reply_count(),
+        })
+        .await;
+    }
+
+    /*
+     * The message starting a thread followed by every reply in it
+     */
+    pub fn thread_messages(&self, root: Uuid) -> Vec<Arc<Message>> {
+        self.inital_messages
+            .iter()
+            .chain(self.messages.iter())
+            .filter(|msg| msg.id() == root || msg.reply_to() == Some(root))
+            .cloned()
+            .collect()
+    }
+
+    /*
+     * Held messages after the sequence number oldest first, or the latest ones without it
+     */
+    pub fn recent_messages(&self, after: Option<i64>, limit: usize) -> Vec<Arc<Message>> {
+        let held = self.inital_messages.iter().chain(self.messages.iter());
+        match after {
+            Some(after) => held
+                .filter(|msg| msg.seq() > after)
+                .take(limit)
+                .cloned()
+                .collect(),
+            None => {
+                let skip = (self.inital_messages.len() + self.messages.len()).saturating_sub(limit);
+                held.skip(skip).cloned().collect()
+            }
+        }
+    }
+
+    pub fn member_names(&self) -> Vec<Arc<String>> {
+        self.members
+            .values()
+            .map(|member| Arc::clone(&member.username))
+            .collect()
+    }
+
+    /*
+     * Renames a member of this room and hands back the name they had before
+     */
+    pub fn rename_member(
+        &mut self,
+        user_id: Uuid,
+        username: Arc<String>,
+    ) -> Result<Arc<String>, String> {
+        if self
+            .members
+            .iter()
+            .any(|(id, member)| *id != user_id && member.username == username)
+        {
+            return Err(format!("{username} is already taken in this room"));
+        }
+        let member = self
+            .members
+            .get_mut(&user_id)
+            .ok_or_else(|| "You are not a member of this room".to_string())?;
+        Ok(std::mem::replace(&mut member.username, username))
+    }
+
+    pub async fn add_user(&mut self, user_handle: Arc<Mutex<User>>) {
         println!("Attempting to lock user");
-        let mut user = user.lock().await;
+        let mut user = user_handle.lock().await;
         println!("Able to unlock user");
         user.set_room(self.sender.clone());
+        // Joining a room that closed but was not dropped yet keeps it open
+        self.is_closed = false;
         self.members.insert(
             user.user_id,
-            (user.user_session_tx.clone(), user.shutdown_tx.clone()),
+            Member {
+                account: Arc::clone(&user.account),
+                username: Arc::clone(&user.username),
+                signed_in: user.signed_in,
+                user: Arc::clone(&user_handle),
+                session_tx: user.user_session_tx.clone(),
+                shutdown_tx: user.shutdown_tx.clone(),
+            },
         );
 
         user.send_intiial_messages(&self.inital_messages)
@@ -64,34 +592,511 @@ impl Room {
         //     .unwrap_or_else(|e| {
         //         println!("Unable to send all messages from the room stored prior {e:?}");
         //     });
-        
+
+        self.emit(WebhookEvent::Joined {
+            account: Arc::clone(&user.account),
+            username: Arc::clone(&user.username),
+        });
+
+        if user.username != user.account {
+            user.user_session_tx
+                .send(ServerEvent::Message(Arc::new(Message::system(
+                    format!(
+                        "Your name was taken, you are {} in this room",
+                        user.username
+                    ),
+                    self.room_id(),
+                ))))
+                .await
+                .unwrap_or_else(|e| println!("Unable to send the assigned name {e:?}"));
+        }
+
+        user.user_session_tx
+            .send(self.room_mode(&user.account))
+            .await
+            .unwrap_or_else(|e| println!("Unable to send the room mode {e:?}"));
+
+        if let Some(topic) = &self.topic {
+            user.user_session_tx
+                .send(ServerEvent::Message(Arc::new(Message::system(
+                    format!("Topic: {topic}"),
+                    self.room_id(),
+                ))))
+                .await
+                .unwrap_or_else(|e| println!("Unable to send the topic {e:?}"));
+        }
+
+        let account = Arc::clone(&user.account);
+        let username = Arc::clone(&user.username);
         drop(user);
         println!("Successfully dropped the user");
+
+        for bot in self.bots.clone() {
+            let actions = bot.on_join(&self.room_id, &username);
+            self.run_bot(bot.name(), Some(Arc::clone(&account)), actions, false)
+                .await;
+        }
     }
 
-    pub async fn run(room: Weak<Mutex<Room>>, mut room_rx: Receiver<Arc<Message>>) {
+    pub async fn run(room: Weak<Mutex<Room>>, mut room_rx: Receiver<RoomEvent>) {
         println!("ROOM RUNNING FOR {room:?}");
-        while let Some(msg) = room_rx.recv().await {
-            let temp_read = msg.as_ref();
-            println!("Received room message: {temp_read:?}");
+        while let Some(event) = room_rx.recv().await {
+            println!("Received room event: {event:?}");
             if let Some(room) = room.upgrade() {
                 let mut borrow_room = room.lock().await;
-                borrow_room.messages.push(Arc::clone(&msg));
-                let members = &borrow_room.members;
-                for (id, user_session_tx) in members {
-                    println!("Sending to user {id}");
-                    let send_to = user_session_tx.0.clone();
-                    send_to
-                        .send(Arc::clone(&msg))
-                        .await
-                        .unwrap_or_else(|_| println!("User {id} is unable to send message"));
+                match event {
+                    RoomEvent::Message(msg) => borrow_room.receive(msg).await,
+                    RoomEvent::Command {
+                        user_id,
+                        name,
+                        args,
+                    } => borrow_room.run_command(user_id, &name, &args).await,
+                    RoomEvent::Typing { user_id, active } => {
+                        borrow_room.set_typing(user_id, active).await
+                    }
+                    RoomEvent::Read { user_id, seq } => borrow_room.mark_read(user_id, seq).await,
+                    RoomEvent::Bot {
+                        bot,
+                        account,
+                        action,
+                    } => borrow_room.run_bot(bot, account, vec![action], false).await,
                 }
+                borrow_room.close_if_empty().await;
                 drop(borrow_room);
             }
         }
     }
 
-    pub async fn disconnect_user(&mut self, user_id: u32) -> Result<(), Err> {
+    /*
+     * Rooms can get messages with nobody in them, from the HTTP API or from someone who just
+     * left. Those are written out straight away and the room closes again.
+     */
+    async fn close_if_empty(&mut self) {
+        if !self.members.is_empty()
+            || (self.is_closed && self.messages.is_empty() && self.changed_messages.is_empty())
+        {
+            return;
+        }
+        match self.flush().await {
+            Ok(_) => self.is_closed = true,
+            Err(e) => println!("Unable to store messages of empty room {e:?}"),
+        }
+    }
+
+    pub fn sender(&self) -> Sender<RoomEvent> {
+        self.sender.clone()
+    }
+
+    // Events sent to the room that it has not got to yet
+    pub fn has_pending_events(&self) -> bool {
+        self.sender.capacity() < self.sender.max_capacity()
+    }
+
+    /*
+     * Messages sent by users. Ones carrying a client id are acknowledged to the sender, and
+     * broadcast only the first time the id is seen within the window.
+     */
+    async fn receive(&mut self, msg: Arc<Message>) {
+        let Some(client_id) = msg.client_id().map(str::to_string) else {
+            if let Some(msg) = self.post(msg).await {
+                self.dispatch_to_bots(&msg).await;
+            }
+            return;
+        };
+        let key = (msg.author().to_string(), client_id.clone());
+        let (id, seq) = match self.recent_client_ids.get(&key) {
+            Some(sent) => {
+                println!("Dropping repeated message {client_id}");
+                (sent.id, sent.seq)
+            }
+            None => {
+                let Some(msg) = self.post(msg).await else {
+                    return;
+                };
+                self.dispatch_to_bots(&msg).await;
+                self.recent_client_ids.insert(
+                    key.clone(),
+                    SentMessage {
+                        received_at: Instant::now(),
+                        id: msg.id(),
+                        seq: msg.seq(),
+                    },
+                );
+                (msg.id(), msg.seq())
+            }
+        };
+
+        let ack = ServerEvent::Ack { client_id, id, seq };
+        for member in self.members.values().filter(|m| *m.account == key.0) {
+            member
+                .session_tx
+                .send(ack.clone())
+                .await
+                .unwrap_or_else(|_| println!("Unable to acknowledge message"));
+        }
+    }
+
+    /*
+     * Shows a message members sent to every bot in the room, whether sent as is or through a
+     * command like /me or /reply. What bots say themselves goes straight to broadcast.
+     */
+    async fn dispatch_to_bots(&mut self, msg: &Arc<Message>) {
+        let account = Some(Arc::new(msg.author().to_string()));
+        for bot in self.bots.clone() {
+            let actions = bot.on_message(&self.room_id, msg);
+            self.run_bot(bot.name(), account.clone(), actions, true)
+                .await;
+        }
+    }
+
+    /*
+     * Carries out what a bot asked for. Whispers go to every session of the account that set
+     * the bot off, and are dropped when there is none. What a bot says is held to the posting
+     * rules of that account, unless it answers a message the account was just allowed to post.
+     */
+    async fn run_bot(
+        &mut self,
+        bot: &'static str,
+        account: Option<Arc<String>>,
+        actions: Vec<BotAction>,
+        admitted: bool,
+    ) {
+        for action in actions {
+            match action {
+                BotAction::Say(content) => {
+                    let msg = Message::new(
+                        Uuid::new(),
+                        Arc::new(bot.to_string()),
+                        content,
+                        self.room_id(),
+                    )
+                    .with_author(bot)
+                    .with_bot(true);
+                    if let Some(account) = &account
+                        && !admitted
+                        && let Err(reason) = self.admit_as(account)
+                    {
+                        self.refuse(account, None, reason).await;
+                        continue;
+                    }
+                    // Bots mostly repeat what members gave them, so they get the same filters
+                    let (msg, note) = self.screen_message(bot, Arc::new(msg));
+                    if let (Some(note), Some(account)) = (note, &account) {
+                        let note = Arc::new(Message::system(note, self.room_id()));
+                        self.send_to_account(account, note).await;
+                    }
+                    if let Some(msg) = msg {
+                        self.broadcast(msg).await;
+                    }
+                }
+                BotAction::Whisper(content) => {
+                    let Some(account) = &account else {
+                        continue;
+                    };
+                    let msg = Arc::new(Message::system(content, self.room_id()));
+                    self.send_to_account(account, msg).await;
+                }
+                BotAction::Later { delay, action } => {
+                    let room_tx = self.sender.clone();
+                    let account = account.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        room_tx
+                            .send(RoomEvent::Bot {
+                                bot,
+                                account,
+                                action: *action,
+                            })
+                            .await
+                            .unwrap_or_else(|_| {
+                                println!("Room closed before bot {bot} got to act")
+                            });
+                    });
+                }
+            }
+        }
+    }
+
+    async fn broadcast(&mut self, mut msg: Arc<Message>) -> Arc<Message> {
+        // Only clones when something else still holds on to the message
+        Arc::make_mut(&mut msg).set_seq(self.next_seq);
+        self.next_seq += 1;
+        self.messages.push(Arc::clone(&msg));
+        if self.messages.len() >= MAX_UNSAVED_MESSAGES {
+            // Kept in memory and tried again on the next message when the store is down
+            self.flush()
+                .await
+                .map(|_| ())
+                .unwrap_or_else(|e| println!("Unable to write out messages early {e:?}"));
+        }
+        self.notify(ServerEvent::Message(Arc::clone(&msg))).await;
+        self.emit(WebhookEvent::Message {
+            message: Arc::clone(&msg),
+        });
+        for account in msg.mentions() {
+            if account == msg.author() {
+                continue;
+            }
+            // Accounts are names anyone can connect with, so a mention only carries what was
+            // said to accounts that could read the room anyway
+            let mentioned = match self.settings.can_read(Some(account), None) {
+                true => Arc::clone(&msg),
+                false => Arc::new(msg.redacted()),
+            };
+            self.services
+                .direct
+                .mention(account.clone(), mentioned)
+                .await
+                .unwrap_or_else(|e| println!("Unable to send mention {e:?}"));
+        }
+        msg
+    }
+
+    // Hands the event to the room's webhooks, if it has any
+    fn emit(&self, event: WebhookEvent) {
+        if self.settings.webhooks.is_empty() {
+            return;
+        }
+        self.services
+            .webhooks
+            .queue(&self.room_id, &self.settings.webhooks, event);
+    }
+
+    /*
+     * Edits and deletions made through commands, marked as moderated when the member changed
+     * someone else's message
+     */
+    fn emit_moderation(&self, user_id: Uuid, event: &ServerEvent) {
+        let Some(by) = self.account(user_id) else {
+            return;
+        };
+        let event = match event {
+            ServerEvent::MessageEdited(message) => WebhookEvent::MessageEdited {
+                moderated: message.author() != by.as_str(),
+                message: Arc::clone(message),
+                by,
+            },
+            ServerEvent::MessageDeleted(message) => WebhookEvent::MessageDeleted {
+                moderated: message.author() != by.as_str(),
+                message: Arc::clone(message),
+                by,
+            },
+            _ => return,
+        };
+        self.emit(event);
+    }
+
+    async fn notify(&self, event: ServerEvent) {
+        for (id, member) in &self.members {
+            println!("Sending to user {id}");
+            member
+                .session_tx
+                .send(event.clone())
+                .await
+                .unwrap_or_else(|_| println!("User {id} is unable to send message"));
+        }
+    }
+
+    async fn notify_others(&self, user_id: Uuid, event: ServerEvent) {
+        for (id, member) in &self.members {
+            if *id == user_id {
+                continue;
+            }
+            member
+                .session_tx
+                .send(event.clone())
+                .await
+                .unwrap_or_else(|_| println!("User {id} is unable to send message"));
+        }
+    }
+
+    /*
+     * Other members only hear about the start and the end of typing, refreshes just push the
+     * expiry back
+     */
+    async fn set_typing(&mut self, user_id: Uuid, active: bool) {
+        let Some(user) = self.display_name(user_id) else {
+            return;
+        };
+        let changed = if active {
+            self.typing.insert(user_id, Instant::now()).is_none()
+        } else {
+            self.typing.remove(&user_id).is_some()
+        };
+        if changed {
+            self.notify_others(user_id, ServerEvent::Typing { user, active })
+                .await;
+        }
+    }
+
+    pub fn latest_seq(&self) -> i64 {
+        self.next_seq - 1
+    }
+
+    /*
+     * Number of messages after the given read marker that the account did not send itself
+     */
+    pub fn unread_count(&self, account: &str, read_marker: i64) -> usize {
+        let read_marker = self
+            .read_markers
+            .get(account)
+            .copied()
+            .unwrap_or_default()
+            .max(read_marker);
+        self.inital_messages
+            .iter()
+            .chain(self.messages.iter())
+            .filter(|msg| msg.seq() > read_marker && msg.author() != account)
+            .count()
+    }
+
+    async fn mark_read(&mut self, user_id: Uuid, seq: i64) {
+        let (Some(account), Some(user)) = (self.account(user_id), self.display_name(user_id))
+        else {
+            return;
+        };
+        // Nothing past the newest message can have been read
+        let seq = seq.min(self.latest_seq());
+        let marker = self.read_markers.entry(account.to_string()).or_default();
+        if seq <= *marker {
+            return;
+        }
+        *marker = seq;
+
+        // Written out in the background so the room does not wait on the store
+        let database = self.services.database.clone();
+        let room_id = self.room_id();
+        let marker_account = Arc::clone(&account);
+        tokio::spawn(async move {
+            account::mark_read(&database, &marker_account, &room_id, seq)
+                .await
+                .unwrap_or_else(|e| println!("Unable to store read marker {e:?}"));
+        });
+
+        self.notify_others(user_id, ServerEvent::ReadReceipt { user, account, seq })
+            .await;
+    }
+
+    pub fn expire_client_ids(&mut self) {
+        self.recent_client_ids
+            .retain(|_, sent| sent.received_at.elapsed() <= CLIENT_ID_WINDOW);
+    }
+
+    // Post times only matter until slow mode would let the account post again
+    pub fn expire_post_times(&mut self) {
+        match self.settings.slow_mode_secs {
+            Some(secs) => {
+                let interval = Duration::from_secs(secs.into());
+                self.last_posted
+                    .retain(|_, posted_at| posted_at.elapsed() < interval);
+            }
+            None => self.last_posted.clear(),
+        }
+    }
+
+    pub async fn expire_typing(&mut self) {
+        let expired: Vec<Uuid> = self
+            .typing
+            .iter()
+            .filter(|(_, since)| since.elapsed() > TYPING_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        for user_id in expired {
+            self.set_typing(user_id, false).await;
+        }
+    }
+
+    async fn send_to(&self, user_id: Uuid, msg: Arc<Message>) {
+        if let Some(member) = self.members.get(&user_id) {
+            member
+                .session_tx
+                .send(ServerEvent::Message(msg))
+                .await
+                .unwrap_or_else(|_| println!("User {user_id} is unable to send message"));
+        }
+    }
+
+    // Every session of the account in this room
+    async fn send_to_account(&self, account: &str, msg: Arc<Message>) {
+        for member in self.members.values().filter(|m| *m.account == account) {
+            member
+                .session_tx
+                .send(ServerEvent::Message(Arc::clone(&msg)))
+                .await
+                .unwrap_or_else(|_| println!("Unable to send message to {account}"));
+        }
+    }
+
+    async fn run_command(&mut self, user_id: Uuid, name: &str, args: &str) {
+        // Cloned out so the handler is free to take the room mutably
+        let commands = self.services.commands.clone();
+        if commands.get(name).is_none()
+            && let Some(bot) = self
+                .bots
+                .iter()
+                .find(|bot| bot.commands().iter().any(|command| command.name == name))
+                .cloned()
+        {
+            let account = self.account(user_id);
+            let actions = bot.on_command(
+                &self.room_id,
+                account.as_deref().map_or("", String::as_str),
+                name,
+                args,
+            );
+            self.run_bot(bot.name(), account, actions, false).await;
+            return;
+        }
+        let result = match commands.get(name) {
+            Some(spec) => (spec.handler)(self, user_id, args),
+            None => Err(commands.unknown_command(name)),
+        };
+
+        let effects = result.unwrap_or_else(|e| vec![CommandEffect::Reply(e)]);
+        for effect in effects {
+            match effect {
+                CommandEffect::Reply(content) => {
+                    let reply = Arc::new(Message::system(content, self.room_id()));
+                    self.send_to(user_id, reply).await;
+                }
+                CommandEffect::Broadcast(msg) => {
+                    // Nothing after a refused message is done either
+                    let Some(msg) = self.post(Arc::new(msg)).await else {
+                        return;
+                    };
+                    if let Some(root) = msg.reply_to() {
+                        self.count_reply(root).await;
+                    }
+                    // Server notices are not something members said
+                    if msg.kind() != MessageKind::System {
+                        self.dispatch_to_bots(&msg).await;
+                    }
+                }
+                CommandEffect::ModeChanged => self.send_room_mode().await,
+                CommandEffect::Notify(event) => {
+                    self.emit_moderation(user_id, &event);
+                    self.notify(event).await
+                }
+                CommandEffect::Rename(username) => {
+                    if let Some(member) = self.members.get(&user_id) {
+                        member.user.lock().await.username = username;
+                    }
+                }
+                CommandEffect::Direct { recipient, content } => {
+                    let Some(member) = self.members.get(&user_id) else {
+                        continue;
+                    };
+                    let dm = DirectMessage::new(member.account.to_string(), recipient, content);
+                    if let Err(e) = self.services.direct.send(dm).await {
+                        println!("Unable to send direct message {e:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn disconnect_user(&mut self, user_id: Uuid) -> Result<(), Err> {
+        self.set_typing(user_id, false).await;
         let user = self.members.remove(&user_id);
         if user.is_none() {
             println!("Nothing inside");
@@ -99,30 +1104,171 @@ impl Room {
         }
 
         let user = user.unwrap();
-        drop(user.0);
+        self.emit(WebhookEvent::Left {
+            account: Arc::clone(&user.account),
+            username: Arc::clone(&user.username),
+        });
+        drop(user.session_tx);
         println!("Successfully dropped the sender");
-        user.1
+        user.shutdown_tx
             .send(true)
             .unwrap_or_else(|e| println!("Unable to send to user close {e:?}"));
         println!("Succesfully assigned false sender");
-        drop(user.1);
+        drop(user.shutdown_tx);
         if self.members.is_empty() {
             println!("Room will close now from Room struct");
-            // println!("{:?}", self.messages);
-            let collection: Collection<Message> = self.database.collection("messages");
-            for message in self.messages.iter() {
-                let take_message = message.as_ref();
-                println!("Writing message {take_message:?}");
-                collection.insert_one(take_message).await.unwrap();
-            }
+            // Left open when the store cannot be reached so the messages are not lost, an admin
+            // can flush it again later
+            self.flush().await?;
             self.is_closed = true;
             println!("Successfully written message to document base");
-            self.messages.clear();
         }
 
         println!("All is fine in paradise");
         Ok(())
     }
+
+    /*
+     * Writes the messages sent since the room opened and any changes to stored ones. Written
+     * messages count as stored from then on, so later edits rewrite them.
+     */
+    pub async fn flush(&mut self) -> Result<usize, Err> {
+        let collection: Collection<Message> = self.services.database.collection("messages");
+        let mut unsaved = std::mem::take(&mut self.messages).into_iter();
+        let mut written = 0;
+        while let Some(message) = unsaved.next() {
+            println!("Writing message {message:?}");
+            if let Err(e) = collection.insert_one(message.as_ref()).await {
+                // Whatever did not make it is kept for the next flush
+                self.messages = std::iter::once(message).chain(unsaved).collect();
+                return Err(e.into());
+            }
+            self.inital_messages.push(message);
+            written += 1;
+        }
+        if written > 0 {
+            self.settings.last_seq = self.latest_seq();
+            settings::save(&self.services.database, &self.settings)
+                .await
+                .unwrap_or_else(|e| println!("Unable to store the room's last sequence {e:?}"));
+        }
+
+        for message in self.inital_messages.iter() {
+            if !self.changed_messages.contains(&message.id()) {
+                continue;
+            }
+            println!("Rewriting message {message:?}");
+            collection
+                .replace_one(doc! {"id": message.id()}, message.as_ref())
+                .await
+                .map(|_| ())
+                .unwrap_or_else(|e| println!("Unable to rewrite message {e:?}"));
+        }
+        self.changed_messages.clear();
+        self.forget_oldest(MAX_BUFFERED_MESSAGES);
+        Ok(written)
+    }
+
+    /*
+     * Drops all but the newest stored messages from memory, they stay in the store
+     */
+    fn forget_oldest(&mut self, keep: usize) -> usize {
+        let excess = self.inital_messages.len().saturating_sub(keep);
+        for message in self.inital_messages.drain(..excess) {
+            self.changed_messages.remove(&message.id());
+        }
+        excess
+    }
+
+    /*
+     * Drops the stored messages the retention settings removed from the store, returning how
+     * many went. Messages not written out yet are left for after the next flush.
+     */
+    pub fn apply_retention(&mut self, now: i64) -> usize {
+        let before = self.inital_messages.len();
+        if let Some(cutoff) = self.settings.retention_cutoff(now) {
+            let changed_messages = &mut self.changed_messages;
+            self.inital_messages.retain(|message| {
+                let keep = message.sent_at() >= cutoff;
+                if !keep {
+                    changed_messages.remove(&message.id());
+                }
+                keep
+            });
+        }
+        if let Some(max) = self.settings.retention_limit() {
+            self.forget_oldest(max);
+        }
+        before - self.inital_messages.len()
+    }
+
+    /*
+     * Legal holds are up to admins, so this skips the moderator check change_settings does
+     */
+    pub fn set_legal_hold(&mut self, hold: bool) {
+        self.settings.legal_hold = hold;
+        self.save_settings();
+    }
+
+    /*
+     * Takes a member out of the room and closes their connection with the reason
+     */
+    pub async fn kick(&mut self, user_id: Uuid, reason: &str) -> Result<(), Err> {
+        let Some(member) = self.members.get(&user_id) else {
+            return Err("No such member in this room".into());
+        };
+        let user = Arc::clone(&member.user);
+        self.emit(WebhookEvent::Removed {
+            account: Arc::clone(&member.account),
+            username: Arc::clone(&member.username),
+            reason: reason.to_string(),
+        });
+        member
+            .session_tx
+            .send(ServerEvent::Closing {
+                reason: reason.to_string(),
+            })
+            .await
+            .unwrap_or_else(|e| println!("Unable to tell user {user_id} they are removed {e:?}"));
+        self.disconnect_user(user_id).await?;
+        user.lock().await.disconnect_user().await
+    }
+
+    /*
+     * Removes everyone and closes the room. A member that cannot be removed cleanly does not
+     * stop the rest from being removed, the room is closed either way.
+     */
+    pub async fn close(&mut self, reason: &str) -> usize {
+        let user_ids: Vec<Uuid> = self.members.keys().copied().collect();
+        for user_id in user_ids.iter() {
+            self.kick(*user_id, reason)
+                .await
+                .unwrap_or_else(|e| println!("Unable to remove {user_id} while closing {e:?}"));
+        }
+        // Kicking the last member stores the messages, unless that is what failed
+        if !self.messages.is_empty() || !self.changed_messages.is_empty() {
+            self.flush()
+                .await
+                .map(|_| ())
+                .unwrap_or_else(|e| println!("Unable to store messages of closed room {e:?}"));
+        }
+        self.is_closed = true;
+        user_ids.len()
+    }
+
+    pub async fn announce(&mut self, content: &str) {
+        let msg = Message::system(format!("Announcement: {content}"), self.room_id());
+        self.broadcast(Arc::new(msg)).await;
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = (&Uuid, &Member)> {
+        self.members.iter()
+    }
+
+    // Messages only held in memory until the room is flushed or closes
+    pub fn unsaved_count(&self) -> usize {
+        self.messages.len()
+    }
 }
 
 impl fmt::Display for Room {
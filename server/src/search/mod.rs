@@ -0,0 +1,342 @@
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{Document, doc},
+    options::IndexOptions,
+};
+
+use crate::{Err, dto::SearchHitDTO, message::Message};
+
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+pub const MAX_SEARCH_LIMIT: usize = 100;
+const SEARCH_INDEX_NAME: &str = "content_text";
+// Words kept around the first match in a snippet
+const SNIPPET_WORDS_BEFORE: usize = 8;
+const SNIPPET_WORDS: usize = 24;
+
+// Which rooms a search looks through
+#[derive(Debug)]
+pub enum SearchScope {
+    Room(String),
+    // Every room but these
+    Except(Vec<String>),
+    // Only these rooms, leaving out the ones in except
+    Within {
+        rooms: Vec<String>,
+        except: Vec<String>,
+    },
+}
+
+#[derive(Debug)]
+pub struct SearchFilter {
+    pub query: String,
+    pub scope: SearchScope,
+    // Name the message was sent under
+    pub sender: Option<String>,
+    // Milliseconds since the unix epoch
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: usize,
+}
+
+/*
+ * Creates the text index over message contents if it is not there yet. The store keeps it up
+ * to date from then on as rooms write out, edit and delete their messages.
+ */
+pub async fn ensure_index(database: &Database) -> Result<(), Err> {
+    let collection: Collection<Message> = database.collection("messages");
+    let index = IndexModel::builder()
+        .keys(doc! {"content": "text"})
+        .options(
+            IndexOptions::builder()
+                .name(SEARCH_INDEX_NAME.to_string())
+                .build(),
+        )
+        .build();
+    collection.create_index(index).await?;
+    Ok(())
+}
+
+/*
+ * Stored messages matching the query, best matches first and newest first among equals.
+ * Messages of an open room only show up once the room has written them out.
+ */
+pub async fn search(database: &Database, filter: &SearchFilter) -> Result<Vec<SearchHitDTO>, Err> {
+    // Read as plain documents so the score comes along with the message
+    let collection: Collection<Document> = database.collection("messages");
+    let found: Vec<Document> = collection
+        .find(search_query(filter))
+        .projection(doc! {"score": {"$meta": "textScore"}})
+        .sort(doc! {"score": {"$meta": "textScore"}, "sent_at": -1})
+        .limit(filter.limit as i64)
+        .await?
+        .try_collect()
+        .await?;
+
+    let terms = search_terms(&filter.query);
+    let mut hits = Vec::new();
+    for document in found {
+        let score = document.get_f64("score").unwrap_or_default();
+        let msg: Message = mongodb::bson::from_document(document)?;
+        hits.push(SearchHitDTO {
+            room_id: msg.room_id().to_string(),
+            id: msg.id().to_string(),
+            seq: msg.seq(),
+            sender: msg.sender.to_string(),
+            sent_at: msg.sent_at(),
+            snippet: snippet(msg.content(), &terms),
+            score,
+        });
+    }
+    Ok(hits)
+}
+
+/*
+ * The messages a search looks at, matching the query text within the scope and the optional
+ * sender and time bounds
+ */
+fn search_query(filter: &SearchFilter) -> Document {
+    let mut query = doc! {"$text": {"$search": &filter.query}};
+    match &filter.scope {
+        SearchScope::Room(room_id) => query.insert("room_id", room_id),
+        SearchScope::Except(room_ids) => query.insert("room_id", doc! {"$nin": room_ids}),
+        SearchScope::Within { rooms, except } => {
+            query.insert("room_id", doc! {"$in": rooms, "$nin": except})
+        }
+    };
+    if let Some(sender) = &filter.sender {
+        query.insert("sender", sender);
+    }
+    let mut sent_at = Document::new();
+    if let Some(from) = filter.from {
+        sent_at.insert("$gte", from);
+    }
+    if let Some(to) = filter.to {
+        sent_at.insert("$lte", to);
+    }
+    if !sent_at.is_empty() {
+        query.insert("sent_at", sent_at);
+    }
+    query
+}
+
+/*
+ * Lowercase words of the query, leaving out excluded words like -word
+ */
+fn search_terms(query: &str) -> Vec<String> {
+    query
+        .split_whitespace()
+        .filter(|word| !word.starts_with('-'))
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        })
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+/*
+ * The words around the first match with every matching word wrapped in **. The index also
+ * matches other forms of a word, which are not always caught here, so a snippet can come back
+ * without any highlight.
+ */
+fn snippet(content: &str, terms: &[String]) -> String {
+    let words: Vec<&str> = content.split_whitespace().collect();
+    let matches = |word: &str| {
+        let word = word.to_lowercase();
+        terms.iter().any(|term| word.contains(term.as_str()))
+    };
+    let first = words.iter().position(|word| matches(word)).unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_WORDS_BEFORE);
+    let end = (start + SNIPPET_WORDS).min(words.len());
+
+    let mut snippet: Vec<String> = words[start..end]
+        .iter()
+        .map(|word| match matches(word) {
+            true => format!("**{word}**"),
+            false => word.to_string(),
+        })
+        .collect();
+    if start > 0 {
+        snippet.insert(0, "…".to_string());
+    }
+    if end < words.len() {
+        snippet.push("…".to_string());
+    }
+    snippet.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(scope: SearchScope) -> SearchFilter {
+        SearchFilter {
+            query: "deploy -staging".to_string(),
+            scope,
+            sender: None,
+            from: None,
+            to: None,
+            limit: DEFAULT_SEARCH_LIMIT,
+        }
+    }
+
+    fn terms(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|term| term.to_string()).collect()
+    }
+
+    // Content of count filler words with the word needle put in at position
+    fn content(count: usize, position: Option<usize>) -> String {
+        (0..count)
+            .map(|indx| match position == Some(indx) {
+                true => "needle".to_string(),
+                false => format!("hay{indx}"),
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn queries_stay_within_the_scope() {
+        let rooms = vec!["lobby".to_string(), "dev".to_string()];
+        let except = vec!["secret".to_string()];
+
+        assert_eq!(
+            search_query(&filter(SearchScope::Room("lobby".to_string()))),
+            doc! {"$text": {"$search": "deploy -staging"}, "room_id": "lobby"}
+        );
+        assert_eq!(
+            search_query(&filter(SearchScope::Except(except.clone()))),
+            doc! {"$text": {"$search": "deploy -staging"}, "room_id": {"$nin": ["secret"]}}
+        );
+        assert_eq!(
+            search_query(&filter(SearchScope::Within { rooms, except })),
+            doc! {
+                "$text": {"$search": "deploy -staging"},
+                "room_id": {"$in": ["lobby", "dev"], "$nin": ["secret"]}
+            }
+        );
+    }
+
+    #[test]
+    fn queries_add_the_sender_and_time_bounds_given() {
+        let mut search = filter(SearchScope::Room("lobby".to_string()));
+        search.sender = Some("alice".to_string());
+        search.from = Some(1000);
+
+        assert_eq!(
+            search_query(&search),
+            doc! {
+                "$text": {"$search": "deploy -staging"},
+                "room_id": "lobby",
+                "sender": "alice",
+                "sent_at": {"$gte": 1000_i64}
+            }
+        );
+
+        search.to = Some(2000);
+        assert_eq!(
+            search_query(&search).get_document("sent_at").unwrap(),
+            &doc! {"$gte": 1000_i64, "$lte": 2000_i64}
+        );
+    }
+
+    #[test]
+    fn terms_leave_out_excluded_words_and_punctuation() {
+        assert_eq!(
+            search_terms("Deploy -staging \"Release Notes\" v2.1!"),
+            terms(&["deploy", "release", "notes", "v21"])
+        );
+        assert_eq!(
+            search_terms("Café ÜBER 東京"),
+            terms(&["café", "über", "東京"])
+        );
+        assert!(search_terms("  -only ... ?!  ").is_empty());
+    }
+
+    #[test]
+    fn snippets_highlight_every_match() {
+        assert_eq!(
+            snippet("Deploy went fine, deploying again", &terms(&["deploy"])),
+            "**Deploy** went fine, **deploying** again"
+        );
+        assert_eq!(
+            snippet("nothing to see", &terms(&["deploy"])),
+            "nothing to see"
+        );
+        assert_eq!(snippet("", &terms(&["deploy"])), "");
+        assert_eq!(snippet("   ", &terms(&["deploy"])), "");
+    }
+
+    #[test]
+    fn snippets_handle_multibyte_words() {
+        assert_eq!(
+            snippet("Wir treffen uns im Café über dem Laden", &terms(&["café"])),
+            "Wir treffen uns im **Café** über dem Laden"
+        );
+        assert_eq!(snippet("ÜBER alles", &terms(&["über"])), "**ÜBER** alles");
+        assert_eq!(
+            snippet("明日 東京 に 行く 🚀🚀", &terms(&["東京"])),
+            "明日 **東京** に 行く 🚀🚀"
+        );
+        // Cut on whole words however many bytes they take
+        let long = content(40, Some(20))
+            .replace("hay", "日本")
+            .replace("needle", "Straße");
+        let cut = snippet(&long, &terms(&["straße"]));
+        assert!(cut.starts_with("… 日本12 "));
+        assert!(cut.contains(" **Straße** "));
+        assert!(cut.ends_with(&format!("日本{} …", 12 + SNIPPET_WORDS - 1)));
+    }
+
+    #[test]
+    fn snippets_only_mark_what_was_left_out() {
+        let needle = terms(&["needle"]);
+
+        // Short enough to be shown whole
+        let whole = content(SNIPPET_WORDS, Some(SNIPPET_WORDS_BEFORE));
+        assert!(!snippet(&whole, &needle).contains('…'));
+
+        // One word too many, cut at the end
+        let long = content(SNIPPET_WORDS + 1, Some(0));
+        let cut = snippet(&long, &needle);
+        assert!(cut.starts_with("**needle** hay1 "));
+        assert!(cut.ends_with(&format!("hay{} …", SNIPPET_WORDS - 1)));
+
+        // The match is as far in as it can be without cutting the start
+        let first = content(40, Some(SNIPPET_WORDS_BEFORE));
+        assert!(snippet(&first, &needle).starts_with("hay0 "));
+
+        // One word further in and the start is cut
+        let later = content(40, Some(SNIPPET_WORDS_BEFORE + 1));
+        let cut = snippet(&later, &needle);
+        assert!(cut.starts_with("… hay1 "));
+        assert_eq!(cut.split_whitespace().count(), SNIPPET_WORDS + 2);
+    }
+
+    #[test]
+    fn snippets_near_the_end_keep_the_last_word() {
+        let ending = content(40, Some(39));
+
+        let cut = snippet(&ending, &terms(&["needle"]));
+
+        let before: Vec<String> = (39 - SNIPPET_WORDS_BEFORE..39)
+            .map(|indx| format!("hay{indx}"))
+            .collect();
+        assert_eq!(cut, format!("… {} **needle**", before.join(" ")));
+    }
+
+    #[test]
+    fn snippets_without_a_match_start_at_the_beginning() {
+        let long = content(40, None);
+
+        let cut = snippet(&long, &terms(&["needle"]));
+
+        assert!(cut.starts_with("hay0 "));
+        assert!(cut.ends_with(" …"));
+        assert!(!cut.contains("**"));
+        assert_eq!(cut.split_whitespace().count(), SNIPPET_WORDS + 1);
+    }
+}
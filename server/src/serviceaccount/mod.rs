@@ -0,0 +1,130 @@
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{DateTime, doc},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Err, roomwebserver::controller::is_valid_room_id};
+
+pub const SERVICE_ACCOUNT_COLLECTION: &str = "service_accounts";
+// Scope entry standing for every room
+pub const ANY_ROOM: &str = "*";
+
+// Account for a program rather than a person. It signs in with a long-lived token and only
+// reaches the rooms in its scopes. Whatever it posts is marked as sent by a bot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceAccount {
+    pub account: String,
+    // SHA-256 of the token, the token itself is only shown once when the account is created
+    token_hash: String,
+    // Rooms it may read from, or "*"
+    pub read: Vec<String>,
+    // Rooms it may post to, or "*"
+    pub post: Vec<String>,
+    // Milliseconds since the unix epoch
+    pub created_at: i64,
+}
+
+impl ServiceAccount {
+    /*
+     * A new account and the token it signs in with
+     */
+    pub fn new(account: String, read: Vec<String>, post: Vec<String>) -> (ServiceAccount, String) {
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        let service_account = ServiceAccount {
+            account,
+            token_hash: hash_token(&token),
+            read,
+            post,
+            created_at: DateTime::now().timestamp_millis(),
+        };
+        (service_account, token)
+    }
+
+    pub fn can_read(&self, room_id: &str) -> bool {
+        in_scope(&self.read, room_id)
+    }
+
+    pub fn can_post(&self, room_id: &str) -> bool {
+        in_scope(&self.post, room_id)
+    }
+}
+
+fn in_scope(scope: &[String], room_id: &str) -> bool {
+    scope.iter().any(|room| room == ANY_ROOM || room == room_id)
+}
+
+/*
+ * Scope entries have to be room ids or "*"
+ */
+pub fn valid_scope(scope: &[String]) -> bool {
+    scope
+        .iter()
+        .all(|room| room == ANY_ROOM || is_valid_room_id(room))
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub async fn ensure_index(database: &Database) -> Result<(), Err> {
+    let collection: Collection<ServiceAccount> = database.collection(SERVICE_ACCOUNT_COLLECTION);
+    for key in ["account", "token_hash"] {
+        let index = IndexModel::builder()
+            .keys(doc! {key: 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        collection.create_index(index).await?;
+    }
+    Ok(())
+}
+
+/*
+ * Fails when the account name is taken
+ */
+pub async fn create(database: &Database, service_account: &ServiceAccount) -> Result<(), Err> {
+    let collection: Collection<ServiceAccount> = database.collection(SERVICE_ACCOUNT_COLLECTION);
+    collection.insert_one(service_account).await?;
+    Ok(())
+}
+
+pub async fn list(database: &Database) -> Result<Vec<ServiceAccount>, Err> {
+    let collection: Collection<ServiceAccount> = database.collection(SERVICE_ACCOUNT_COLLECTION);
+    let found = collection
+        .find(doc! {})
+        .sort(doc! {"account": 1})
+        .await?
+        .try_collect()
+        .await?;
+    Ok(found)
+}
+
+pub async fn exists(database: &Database, account: &str) -> Result<bool, Err> {
+    let collection: Collection<ServiceAccount> = database.collection(SERVICE_ACCOUNT_COLLECTION);
+    Ok(collection
+        .find_one(doc! {"account": account})
+        .await?
+        .is_some())
+}
+
+/*
+ * Tokens are looked up on every request, so a revoked one stops working straight away
+ */
+pub async fn revoke(database: &Database, account: &str) -> Result<bool, Err> {
+    let collection: Collection<ServiceAccount> = database.collection(SERVICE_ACCOUNT_COLLECTION);
+    let deleted = collection.delete_one(doc! {"account": account}).await?;
+    Ok(deleted.deleted_count > 0)
+}
+
+pub async fn find_by_token(
+    database: &Database,
+    token: &str,
+) -> Result<Option<ServiceAccount>, Err> {
+    let collection: Collection<ServiceAccount> = database.collection(SERVICE_ACCOUNT_COLLECTION);
+    Ok(collection
+        .find_one(doc! {"token_hash": hash_token(token)})
+        .await?)
+}
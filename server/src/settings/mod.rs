@@ -0,0 +1,608 @@
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Collection, Database,
+    bson::{DateTime, doc},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Err, admin::same_token, filter::AutoModRule, webhook::Webhook};
+
+pub const SETTINGS_COLLECTION: &str = "room_settings";
+pub const DEFAULT_INVITE_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_INVITE_EXPIRY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+pub const NOT_MODERATOR: &str = "Only moderators can change the room settings";
+
+// A token that gets whoever presents it into the room until it expires or runs out of uses
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Invite {
+    pub token: String,
+    pub created_by: String,
+    // Milliseconds since the unix epoch
+    pub expires_at: i64,
+    // No limit when not set
+    pub max_uses: Option<u32>,
+    #[serde(default)]
+    pub uses: u32,
+}
+
+impl Invite {
+    fn is_usable(&self, now: i64) -> bool {
+        self.expires_at > now && self.max_uses.is_none_or(|max| self.uses < max)
+    }
+
+    pub fn describe(&self) -> String {
+        let minutes_left = (self.expires_at - DateTime::now().timestamp_millis()) / 60_000;
+        let uses = match self.max_uses {
+            Some(max) => format!("{}/{max} uses", self.uses),
+            None => format!("{} uses", self.uses),
+        };
+        format!(
+            "{} by {}, {uses}, expires in {}h{}m",
+            self.token,
+            self.created_by,
+            minutes_left / 60,
+            minutes_left % 60
+        )
+    }
+}
+
+// What happens when someone joins under a name another account is using in the room
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateNames {
+    Reject,
+    // Adds -2, -3 and so on until the name is free
+    #[default]
+    Suffix,
+}
+
+// Who is allowed into a room. Rooms without a document are open to anyone.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RoomSettings {
+    pub room_id: String,
+    #[serde(default)]
+    pub max_members: Option<u32>,
+    // Only accounts on the invited list get in
+    #[serde(default)]
+    pub invite_only: bool,
+    // Accounts that get in without a password, even when the room is invite only
+    #[serde(default)]
+    pub invited: Vec<String>,
+    // Only set for passwords stored before they went through argon2
+    #[serde(default)]
+    password_salt: Option<String>,
+    // Argon2 hash string with its own salt, the password itself is never kept
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    invites: Vec<Invite>,
+    #[serde(default)]
+    pub duplicate_names: DuplicateNames,
+    // Stored messages older than this many days are removed
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+    // Only this many of the newest stored messages are kept
+    #[serde(default)]
+    pub retention_messages: Option<u32>,
+    // Set by an admin, nothing is removed while it is on whatever the retention says
+    #[serde(default)]
+    pub legal_hold: bool,
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    // Checked in order after the server wide filters
+    #[serde(default)]
+    pub automod: Vec<AutoModRule>,
+    // Seconds members have to wait between messages, moderators never wait
+    #[serde(default)]
+    pub slow_mode_secs: Option<u32>,
+    // Only moderators can post, for announcement rooms
+    #[serde(default)]
+    pub read_only: bool,
+    // Highest sequence number the room handed out. Numbering carries on from it even when
+    // retention removed every stored message, read markers are never moved back.
+    #[serde(default)]
+    pub last_seq: i64,
+}
+
+// What a user can present to get into a room that is not open to everyone
+#[derive(Debug, Default)]
+pub struct Credentials {
+    pub password: Option<String>,
+    pub invite: Option<String>,
+}
+
+// Why a join was turned away, shown to the user as the close reason
+#[derive(Debug)]
+pub enum Refusal {
+    Full(u32),
+    NotInvited,
+    PasswordRequired,
+    WrongPassword,
+    InvalidInvite,
+    NameTaken(String),
+    // Service accounts only use the API, nobody joins under their name
+    ServiceAccount,
+    // Moderators join with their API token, nobody else gets to use their name
+    Moderator,
+    // The settings could not be read, so nobody is let in rather than everybody
+    Unavailable,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Refusal::Full(max) => write!(f, "Room is full ({max} members at most)"),
+            Refusal::NotInvited => write!(f, "Room is invite only"),
+            Refusal::PasswordRequired => write!(f, "Room needs a password to join"),
+            Refusal::WrongPassword => write!(f, "Wrong password for this room"),
+            Refusal::InvalidInvite => write!(f, "Invite is invalid, expired or used up"),
+            Refusal::NameTaken(name) => write!(f, "{name} is already taken in this room"),
+            Refusal::ServiceAccount => write!(f, "That name belongs to a service account"),
+            Refusal::Moderator => {
+                write!(
+                    f,
+                    "That name belongs to a moderator, sign in with its API token"
+                )
+            }
+            Refusal::Unavailable => write!(f, "Room is unavailable right now, try again"),
+        }
+    }
+}
+
+impl RoomSettings {
+    pub fn new(room_id: &str) -> RoomSettings {
+        RoomSettings {
+            room_id: room_id.to_string(),
+            ..RoomSettings::default()
+        }
+    }
+
+    pub fn has_password(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    pub fn set_password(&mut self, password: Option<&str>) -> Result<(), String> {
+        self.password_hash = password.map(hash_password).transpose()?;
+        self.password_salt = None;
+        Ok(())
+    }
+
+    fn password_matches(&self, password: &str) -> bool {
+        let Some(hash) = &self.password_hash else {
+            return true;
+        };
+        match &self.password_salt {
+            // Keeps working until the password is set again
+            Some(salt) => same_token(&legacy_hash_password(salt, password), hash),
+            None => PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+
+    /*
+     * Whether the history of the room can be looked through. Same rules as joining, except
+     * that nothing is used up and a full room can still be read.
+     */
+    pub fn can_read(&self, account: Option<&str>, password: Option<&str>) -> bool {
+        if let Some(account) = account
+            && self.invited.iter().any(|invited| invited == account)
+        {
+            return true;
+        }
+        if self.invite_only {
+            return false;
+        }
+        password.map_or(!self.has_password(), |password| {
+            self.password_matches(password)
+        })
+    }
+
+    /*
+     * Makes a change for a moderator. Callers only count an account as a moderator once it
+     * signed in with its API token, the name people connect with proves nothing.
+     */
+    pub fn change_as<T>(
+        &mut self,
+        is_moderator: bool,
+        change: impl FnOnce(&mut RoomSettings) -> T,
+    ) -> Result<T, String> {
+        if !is_moderator {
+            return Err(NOT_MODERATOR.to_string());
+        }
+        Ok(change(self))
+    }
+
+    /*
+     * Decides whether the account can join with the room holding members people. Invited
+     * accounts skip the password. Using an invite puts the account on the invited list so
+     * coming back later does not take up another use.
+     */
+    pub fn admit(
+        &mut self,
+        account: &str,
+        credentials: &Credentials,
+        members: usize,
+    ) -> Result<(), Refusal> {
+        if let Some(max) = self.max_members
+            && members >= max as usize
+        {
+            return Err(Refusal::Full(max));
+        }
+        if self.invited.iter().any(|invited| invited == account) {
+            return Ok(());
+        }
+        if !self.invite_only && !self.has_password() {
+            return Ok(());
+        }
+        if let Some(token) = &credentials.invite
+            && self.use_invite(token)
+        {
+            self.invited.push(account.to_string());
+            return Ok(());
+        }
+        let presented_invite = credentials.invite.is_some();
+        if self.invite_only {
+            return match presented_invite {
+                true => Err(Refusal::InvalidInvite),
+                false => Err(Refusal::NotInvited),
+            };
+        }
+        match &credentials.password {
+            Some(password) if self.password_matches(password) => Ok(()),
+            Some(_) => Err(Refusal::WrongPassword),
+            None if presented_invite => Err(Refusal::InvalidInvite),
+            None => Err(Refusal::PasswordRequired),
+        }
+    }
+
+    fn use_invite(&mut self, token: &str) -> bool {
+        let now = DateTime::now().timestamp_millis();
+        match self
+            .invites
+            .iter_mut()
+            .find(|invite| invite.token == token && invite.is_usable(now))
+        {
+            Some(invite) => {
+                invite.uses += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /*
+     * Adds a new invite, clearing out the ones nobody can use anymore while at it
+     */
+    pub fn create_invite(
+        &mut self,
+        created_by: &str,
+        expires_in: Duration,
+        max_uses: Option<u32>,
+    ) -> Invite {
+        let now = DateTime::now().timestamp_millis();
+        self.invites.retain(|invite| invite.is_usable(now));
+        let invite = Invite {
+            token: hex::encode(rand::random::<[u8; 16]>()),
+            created_by: created_by.to_string(),
+            expires_at: now + expires_in.min(MAX_INVITE_EXPIRY).as_millis() as i64,
+            max_uses,
+            uses: 0,
+        };
+        self.invites.push(invite.clone());
+        invite
+    }
+
+    pub fn revoke_invite(&mut self, token: &str) -> bool {
+        let before = self.invites.len();
+        self.invites.retain(|invite| invite.token != token);
+        self.invites.len() != before
+    }
+
+    // Invites that would still get someone in right now
+    pub fn outstanding_invites(&self) -> Vec<Invite> {
+        let now = DateTime::now().timestamp_millis();
+        self.invites
+            .iter()
+            .filter(|invite| invite.is_usable(now))
+            .cloned()
+            .collect()
+    }
+
+    /*
+     * Whether a member who last posted the given time ago can post now, with the reason when
+     * they cannot. Moderators are not held to this.
+     */
+    pub fn check_posting(&self, since_last_post: Option<Duration>) -> Result<(), String> {
+        if self.read_only {
+            return Err("This room is read only, only moderators can post".to_string());
+        }
+        let Some(secs) = self.slow_mode_secs else {
+            return Ok(());
+        };
+        let interval = Duration::from_secs(secs.into());
+        match since_last_post {
+            Some(since) if since < interval => {
+                // Rounded up so nobody is told to wait 0s
+                let wait = (interval - since).as_millis().div_ceil(1000);
+                Err(format!(
+                    "Slow mode is on, wait {wait}s before posting again"
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /*
+     * Messages sent before the returned time are past the max age, if the room has one and
+     * is not under a legal hold
+     */
+    pub fn retention_cutoff(&self, now: i64) -> Option<i64> {
+        if self.legal_hold {
+            return None;
+        }
+        self.retention_days
+            .map(|days| now - days as i64 * 24 * 60 * 60 * 1000)
+    }
+
+    pub fn retention_limit(&self) -> Option<usize> {
+        if self.legal_hold {
+            return None;
+        }
+        self.retention_messages.map(|max| max as usize)
+    }
+
+    // One line summary for /settings
+    pub fn describe(&self) -> String {
+        let limit = match self.max_members {
+            Some(max) => format!("at most {max} members"),
+            None => "no member limit".to_string(),
+        };
+        let access = match (self.invite_only, self.has_password()) {
+            (true, _) => "invite only",
+            (false, true) => "password protected",
+            (false, false) => "open to anyone",
+        };
+        let names = match self.duplicate_names {
+            DuplicateNames::Reject => "Names already in use are refused",
+            DuplicateNames::Suffix => "Names already in use get a number added",
+        };
+        let retention = match (self.retention_days, self.retention_messages) {
+            (None, None) => "Messages are kept forever".to_string(),
+            (Some(days), None) => format!("Messages are kept for {days} days"),
+            (None, Some(max)) => format!("The newest {max} messages are kept"),
+            (Some(days), Some(max)) => {
+                format!("The newest {max} messages are kept for up to {days} days")
+            }
+        };
+        let posting = match (self.read_only, self.slow_mode_secs) {
+            (true, _) => " Only moderators can post.".to_string(),
+            (false, Some(secs)) => format!(" Members can post once every {secs}s."),
+            (false, None) => String::new(),
+        };
+        let hold = if self.legal_hold {
+            " (on legal hold, nothing is removed)"
+        } else {
+            ""
+        };
+        format!(
+            "Room is {access} with {limit}.{posting} {names}. {retention}{hold}. {} webhooks, {} auto moderation rules. Invited: {}",
+            self.webhooks.len(),
+            self.automod.len(),
+            if self.invited.is_empty() {
+                "nobody".to_string()
+            } else {
+                self.invited.join(", ")
+            }
+        )
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| format!("Unable to set the password: {e}"))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Unable to set the password: {e}"))
+}
+
+// Hex sha256 of the salt followed by the password, how passwords used to be stored
+fn legacy_hash_password(salt: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+pub async fn find(database: &Database, room_id: &str) -> Result<RoomSettings, Err> {
+    let collection: Collection<RoomSettings> = database.collection(SETTINGS_COLLECTION);
+    let found = collection.find_one(doc! {"room_id": room_id}).await?;
+    Ok(found.unwrap_or_else(|| RoomSettings::new(room_id)))
+}
+
+/*
+ * Rooms the account cannot read without a password or an invite, every invite only and
+ * password protected room when there is no account
+ */
+pub async fn restricted_for(
+    database: &Database,
+    account: Option<&str>,
+) -> Result<Vec<String>, Err> {
+    let collection: Collection<RoomSettings> = database.collection(SETTINGS_COLLECTION);
+    let filter = doc! {"$or": [{"invite_only": true}, {"password_hash": {"$ne": null}}]};
+    let restricted: Vec<RoomSettings> = collection.find(filter).await?.try_collect().await?;
+    Ok(restricted
+        .into_iter()
+        .filter(|settings| !settings.can_read(account, None))
+        .map(|settings| settings.room_id)
+        .collect())
+}
+
+pub async fn save(database: &Database, settings: &RoomSettings) -> Result<(), Err> {
+    let collection: Collection<RoomSettings> = database.collection(SETTINGS_COLLECTION);
+    collection
+        .replace_one(doc! {"room_id": &settings.room_id}, settings)
+        .upsert(true)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(password: Option<&str>, invite: Option<&str>) -> Credentials {
+        Credentials {
+            password: password.map(str::to_string),
+            invite: invite.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn only_moderators_change_settings() {
+        let mut settings = RoomSettings::new("lobby");
+        assert!(
+            settings
+                .change_as(false, |settings| settings.read_only = true)
+                .is_err()
+        );
+        assert!(!settings.read_only);
+        assert!(
+            settings
+                .change_as(true, |settings| settings.read_only = true)
+                .is_ok()
+        );
+        assert!(settings.read_only);
+    }
+
+    #[test]
+    fn open_rooms_let_anyone_in_until_full() {
+        let mut settings = RoomSettings::new("lobby");
+        assert!(settings.admit("alice", &Credentials::default(), 10).is_ok());
+        settings.max_members = Some(2);
+        assert!(settings.admit("alice", &Credentials::default(), 1).is_ok());
+        assert!(matches!(
+            settings.admit("alice", &Credentials::default(), 2),
+            Err(Refusal::Full(2))
+        ));
+    }
+
+    #[test]
+    fn password_rooms_check_the_password() {
+        let mut settings = RoomSettings::new("lobby");
+        settings.set_password(Some("hunter2")).unwrap();
+        assert!(matches!(
+            settings.admit("alice", &Credentials::default(), 0),
+            Err(Refusal::PasswordRequired)
+        ));
+        assert!(matches!(
+            settings.admit("alice", &credentials(Some("wrong"), None), 0),
+            Err(Refusal::WrongPassword)
+        ));
+        assert!(
+            settings
+                .admit("alice", &credentials(Some("hunter2"), None), 0)
+                .is_ok()
+        );
+        assert!(settings.can_read(None, Some("hunter2")));
+        assert!(!settings.can_read(None, None));
+        settings.set_password(None).unwrap();
+        assert!(settings.admit("alice", &Credentials::default(), 0).is_ok());
+    }
+
+    #[test]
+    fn passwords_are_stored_with_argon2() {
+        let mut settings = RoomSettings::new("lobby");
+        settings.set_password(Some("hunter2")).unwrap();
+        let hash = settings.password_hash.clone().unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(!hash.contains("hunter2"));
+        assert!(settings.password_salt.is_none());
+    }
+
+    #[test]
+    fn legacy_passwords_still_match() {
+        let mut settings = RoomSettings::new("lobby");
+        settings.password_hash = Some(legacy_hash_password("salt", "hunter2"));
+        settings.password_salt = Some("salt".to_string());
+        assert!(settings.password_matches("hunter2"));
+        assert!(!settings.password_matches("hunter3"));
+        settings.set_password(Some("hunter3")).unwrap();
+        assert!(settings.password_matches("hunter3"));
+        assert!(settings.password_salt.is_none());
+    }
+
+    #[test]
+    fn invites_are_used_up() {
+        let mut settings = RoomSettings::new("lobby");
+        settings.invite_only = true;
+        let invite = settings.create_invite("mod", DEFAULT_INVITE_EXPIRY, Some(1));
+        assert!(matches!(
+            settings.admit("alice", &Credentials::default(), 0),
+            Err(Refusal::NotInvited)
+        ));
+        assert!(
+            settings
+                .admit("alice", &credentials(None, Some(&invite.token)), 0)
+                .is_ok()
+        );
+        // Coming back does not need the invite again
+        assert!(settings.admit("alice", &Credentials::default(), 0).is_ok());
+        assert!(matches!(
+            settings.admit("bob", &credentials(None, Some(&invite.token)), 0),
+            Err(Refusal::InvalidInvite)
+        ));
+        assert!(settings.outstanding_invites().is_empty());
+    }
+
+    #[test]
+    fn read_only_rooms_refuse_posts() {
+        let mut settings = RoomSettings::new("lobby");
+        assert!(settings.check_posting(None).is_ok());
+        settings.read_only = true;
+        assert!(settings.check_posting(None).is_err());
+        assert!(
+            settings
+                .check_posting(Some(Duration::from_secs(3600)))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn slow_mode_makes_members_wait() {
+        let mut settings = RoomSettings::new("lobby");
+        settings.slow_mode_secs = Some(30);
+        assert!(settings.check_posting(None).is_ok());
+        let refused = settings.check_posting(Some(Duration::from_secs(10)));
+        assert_eq!(
+            refused,
+            Err("Slow mode is on, wait 20s before posting again".to_string())
+        );
+        assert!(
+            settings
+                .check_posting(Some(Duration::from_secs(30)))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn full_rooms_turn_away_invited_accounts() {
+        let mut settings = RoomSettings::new("lobby");
+        settings.invited.push("alice".to_string());
+        settings.max_members = Some(1);
+        assert!(matches!(
+            settings.admit("alice", &Credentials::default(), 1),
+            Err(Refusal::Full(1))
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    ServerConfig,
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+use crate::Err;
+
+// How often the certificate and key files are checked for changes
+pub const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// PEM files from the TLS_CERT and TLS_KEY variables. The server only speaks TLS when both are set.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn from_env() -> Option<TlsConfig> {
+        let cert_path = std::env::var("TLS_CERT")
+            .ok()
+            .filter(|path| !path.is_empty());
+        let key_path = std::env::var("TLS_KEY")
+            .ok()
+            .filter(|path| !path.is_empty());
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path: PathBuf::from(cert_path),
+                key_path: PathBuf::from(key_path),
+            }),
+            (None, None) => {
+                println!("TLS_CERT and TLS_KEY not set, serving plain HTTP");
+                None
+            }
+            _ => {
+                println!("Only one of TLS_CERT and TLS_KEY is set, serving plain HTTP");
+                None
+            }
+        }
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&self.cert_path).and_then(|m| m.modified());
+        let key = std::fs::metadata(&self.key_path).and_then(|m| m.modified());
+        cert.ok().zip(key.ok())
+    }
+
+    /*
+     * Reads the certificate chain and private key into something rustls can serve
+     */
+    fn load(&self) -> Result<CertifiedKey, Err> {
+        let chain =
+            CertificateDer::pem_file_iter(&self.cert_path)?.collect::<Result<Vec<_>, _>>()?;
+        if chain.is_empty() {
+            return Err(format!("No certificates in {}", self.cert_path.display()).into());
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)?;
+        let key = ring::sign::any_supported_type(&key)?;
+        Ok(CertifiedKey::new(chain, key))
+    }
+}
+
+/*
+ * Hands every handshake whichever certificate was loaded last, so a renewed certificate is
+ * picked up without restarting the server
+ */
+#[derive(Debug)]
+pub struct CertReloader {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertReloader {
+    pub fn new(config: TlsConfig) -> Result<CertReloader, Err> {
+        let current = RwLock::new(Arc::new(config.load()?));
+        Ok(CertReloader { config, current })
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig, Err> {
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+        Ok(config)
+    }
+
+    /*
+     * Reloads the certificate whenever either file changes. A file that fails to load, like
+     * one caught halfway through being written, leaves the old certificate in place until
+     * the next change.
+     */
+    pub async fn watch(self: Arc<Self>) {
+        let mut last_modified = self.config.modified();
+        let mut interval = tokio::time::interval(TLS_RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            let modified = self.config.modified();
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            match self.config.load() {
+                Ok(certified_key) => {
+                    println!(
+                        "Reloaded TLS certificate {}",
+                        self.config.cert_path.display()
+                    );
+                    match self.current.write() {
+                        Ok(mut current) => *current = Arc::new(certified_key),
+                        Err(e) => println!("Unable to swap the TLS certificate {e:?}"),
+                    }
+                }
+                Err(e) => println!("Unable to reload TLS certificate, keeping the old one {e:?}"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|current| Arc::clone(&current))
+    }
+}
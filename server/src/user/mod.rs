@@ -10,17 +10,28 @@ use tokio::sync::{
     mpsc::{self, Receiver, Sender},
 };
 
-use crate::{Err, message::Message, roomwebserver::server::Room};
+use crate::{
+    Err,
+    command::{self, Input},
+    event::{ClientEvent, ServerEvent},
+    message::Message,
+    roomwebserver::server::{Room, RoomEvent},
+};
 
 #[derive(Debug)]
 pub struct User {
-    pub user_id: u32,
+    pub user_id: Uuid,
+    // Name the user connected with. Direct messages are addressed to this, it never changes
+    pub account: Arc<String>,
+    // Display name in the room, can be changed through /nick
     pub username: Arc<String>,
     pub room_id: Arc<String>,
-    pub user_session_tx: mpsc::Sender<Arc<Message>>,
-    room_sender: Option<mpsc::Sender<Arc<Message>>>,
+    pub user_session_tx: mpsc::Sender<ServerEvent>,
+    room_sender: Option<mpsc::Sender<RoomEvent>>,
     pub shutdown_tx: tokio::sync::watch::Sender<bool>,
     pub disconnected: bool,
+    // Signed in with an API token of the account, only then can it count as a moderator
+    pub signed_in: bool,
 }
 
 impl Display for User {
@@ -31,10 +42,10 @@ impl Display for User {
 
 impl User {
     pub fn new(
-        user_id: u32,
+        user_id: Uuid,
         username: String,
         room_id: Arc<String>,
-        user_tx: Sender<Arc<Message>>,
+        user_tx: Sender<ServerEvent>,
         shutdown_tx: tokio::sync::watch::Sender<bool>,
     ) -> User {
         // Session is to send messages into a websocket
@@ -42,15 +53,26 @@ impl User {
         let username = Arc::new(username);
         User {
             user_id,
+            account: Arc::clone(&username),
             username: Arc::clone(&username),
             room_id,
             user_session_tx: user_tx,
             room_sender: None,
             shutdown_tx,
             disconnected: false,
+            signed_in: false,
         }
     }
 
+    // The account of the API token the user signed in with, which is also their name
+    pub fn signed_in_as(mut self, account: String) -> User {
+        let account = Arc::new(account);
+        self.account = Arc::clone(&account);
+        self.username = account;
+        self.signed_in = true;
+        self
+    }
+
     /*
      * Asynchronously spawns 2 threads to manage both user sending of message 1
      */
@@ -58,7 +80,7 @@ impl User {
         user: Arc<Mutex<User>>,
         mut session: Session,
         mut write_session: MessageStream,
-        mut user_rx: Receiver<Arc<Message>>,
+        mut user_rx: Receiver<ServerEvent>,
         shutdown_rx: tokio::sync::watch::Receiver<bool>,
         room: Weak<Mutex<Room>>,
     ) {
@@ -66,7 +88,12 @@ impl User {
         let shutdown_rx_2 = shutdown_rx.clone();
         // let borrow_username = Arc::clone(&user.username);
         tokio::spawn(async move {
+            let mut close_reason = String::from("User has closed the channel!");
             while let Some(msg) = user_rx.recv().await {
+                if let ServerEvent::Closing { reason } = msg {
+                    close_reason = reason;
+                    break;
+                }
                 if shutdown_rx_1.has_changed().unwrap_or_else(|e| {
                     println!("Channel has already been closed err {e:?}!");
                     true
@@ -75,7 +102,7 @@ impl User {
                 }
                 // let msg = &*msg;
                 session
-                    .text(serde_json::to_string(&*msg).unwrap_or("message not found".to_string()))
+                    .text(serde_json::to_string(&msg).unwrap_or("message not found".to_string()))
                     .await
                     .unwrap_or_else(|e| {
                         println!("Channel has been closed! {e:?}");
@@ -86,7 +113,7 @@ impl User {
             match session
                 .close(Some(CloseReason {
                     code: CloseCode::Normal,
-                    description: Some(String::from("User has closed the channel!")),
+                    description: Some(close_reason),
                 }))
                 .await
             {
@@ -97,7 +124,8 @@ impl User {
 
         rt::spawn(async move {
             let guard_user = user.lock().await;
-            let borrow_username = Arc::clone(&guard_user.username);
+            let user_id = guard_user.user_id;
+            let account = Arc::clone(&guard_user.account);
             let borrow_room_id = Arc::clone(&guard_user.room_id);
             let room_info = guard_user
                 .room_sender
@@ -116,24 +144,19 @@ impl User {
                     Ok(msg) => match msg {
                         actix_ws::Message::Text(txt) => {
                             println!("Message received! {txt}");
-                            let txt = txt.to_string();
-                            // let room_name = room_name.as_deref().unwrap().clone();
-                            let msg = Arc::new(Message::new(
-                                Uuid::new(),
-                                Arc::clone(&borrow_username),
-                                txt,
-                                Arc::clone(&borrow_room_id),
-                            ));
+                            let event =
+                                User::read_text(&user, user_id, &account, &borrow_room_id, &txt)
+                                    .await;
                             room_info
-                                .send(msg)
+                                .send(event)
                                 .await
                                 .unwrap_or_else(|e| println!("Unable to send message {e:?}"));
                             println!("Successful send");
                         }
-                        actix_ws::Message::Binary(byt) => {}
-                        actix_ws::Message::Continuation(cont) => {}
-                        actix_ws::Message::Ping(ping) => {}
-                        actix_ws::Message::Pong(pong) => {}
+                        actix_ws::Message::Binary(_byt) => {}
+                        actix_ws::Message::Continuation(_cont) => {}
+                        actix_ws::Message::Ping(_ping) => {}
+                        actix_ws::Message::Pong(_pong) => {}
                         actix_ws::Message::Close(msg) => {
                             println!("The channel has been closed becasue of {msg:?}");
                             break;
@@ -145,26 +168,95 @@ impl User {
                     }
                 }
             }
-            if let Some(room) = room.upgrade() {
-                let mut borrow_room = room.lock().await;
-                let mut guard_user = user.lock().await;
-                borrow_room
-                    .disconnect_user(guard_user.user_id)
-                    .await
-                    .unwrap_or_else(|e| {
-                        println!("Unable to close disconnect user from room because of {e:?}")
-                    });
-                guard_user
-                    .disconnect_user()
-                    .await
-                    .unwrap_or_else(|e| println!("Unable to close user because of {e:?}"));
-                drop(borrow_room);
-                drop(guard_user);
-            }
+            User::leave_room(&user, &room).await;
         });
     }
 
-    pub fn set_room(&mut self, room_sender: mpsc::Sender<Arc<Message>>) {
+    /*
+     * Takes the user out of the room and marks them disconnected so the sweeper drops them
+     */
+    pub async fn leave_room(user: &Arc<Mutex<User>>, room: &Weak<Mutex<Room>>) {
+        if let Some(room) = room.upgrade() {
+            let mut borrow_room = room.lock().await;
+            let mut guard_user = user.lock().await;
+            borrow_room
+                .disconnect_user(guard_user.user_id)
+                .await
+                .unwrap_or_else(|e| {
+                    println!("Unable to close disconnect user from room because of {e:?}")
+                });
+            guard_user
+                .disconnect_user()
+                .await
+                .unwrap_or_else(|e| println!("Unable to close user because of {e:?}"));
+            drop(borrow_room);
+            drop(guard_user);
+        }
+    }
+
+    /*
+     * Works out what a text frame from the client is asking the room to do
+     */
+    async fn read_text(
+        user: &Arc<Mutex<User>>,
+        user_id: Uuid,
+        account: &str,
+        room_id: &Arc<String>,
+        txt: &str,
+    ) -> RoomEvent {
+        match serde_json::from_str::<ClientEvent>(txt) {
+            Ok(event) => User::read_event(user, user_id, account, room_id, event).await,
+            Err(_) => User::read_input(user, user_id, account, room_id, txt, None).await,
+        }
+    }
+
+    pub async fn read_event(
+        user: &Arc<Mutex<User>>,
+        user_id: Uuid,
+        account: &str,
+        room_id: &Arc<String>,
+        event: ClientEvent,
+    ) -> RoomEvent {
+        match event {
+            ClientEvent::Typing { active } => RoomEvent::Typing { user_id, active },
+            ClientEvent::Read { seq } => RoomEvent::Read { user_id, seq },
+            ClientEvent::Send { client_id, content } => {
+                User::read_input(user, user_id, account, room_id, &content, Some(client_id)).await
+            }
+        }
+    }
+
+    async fn read_input(
+        user: &Arc<Mutex<User>>,
+        user_id: Uuid,
+        account: &str,
+        room_id: &Arc<String>,
+        txt: &str,
+        client_id: Option<String>,
+    ) -> RoomEvent {
+        match command::parse(txt) {
+            Input::Command { name, args } => RoomEvent::Command {
+                user_id,
+                name: name.to_string(),
+                args: args.to_string(),
+            },
+            Input::Text(txt) => {
+                // Looked up every time since /nick can change it mid session
+                let username = Arc::clone(&user.lock().await.username);
+                RoomEvent::Message(Arc::new(
+                    Message::new(Uuid::new(), username, txt.to_string(), Arc::clone(room_id))
+                        .with_author(account)
+                        .with_client_id(client_id),
+                ))
+            }
+        }
+    }
+
+    pub fn room_sender(&self) -> Option<mpsc::Sender<RoomEvent>> {
+        self.room_sender.clone()
+    }
+
+    pub fn set_room(&mut self, room_sender: mpsc::Sender<RoomEvent>) {
         self.room_sender = Some(room_sender)
     }
 
@@ -174,7 +266,12 @@ impl User {
     pub async fn send_intiial_messages(&self, msgs: &Vec<Arc<Message>>) -> Result<(), Err> {
         for msg in msgs {
             println!("Sending message {msg:?}");
-            if self.user_session_tx.send(Arc::clone(msg)).await.is_err() {
+            if self
+                .user_session_tx
+                .send(ServerEvent::Message(Arc::clone(msg)))
+                .await
+                .is_err()
+            {
                 return Err("Unable to send message".into());
             }
             println!("Succesfully sent message {msg:?}");
@@ -0,0 +1,263 @@
+// Browser client for /ws/joinroom. Text typed in is sent as is so commands work like in the
+// terminal client, the server answers with JSON events tagged by "type".
+"use strict";
+
+const TYPING_REFRESH_MS = 3000;
+const DEFAULT_PLACEHOLDER = "Message, or /help for commands";
+
+const joinForm = document.getElementById("join");
+const room = document.getElementById("room");
+const roomTitle = document.getElementById("room-title");
+const messages = document.getElementById("messages");
+const typingLine = document.getElementById("typing");
+const sendForm = document.getElementById("send");
+const leaveButton = document.getElementById("leave");
+
+let socket = null;
+let username = "";
+// Message id to its line, so edits and deletions can redraw it in place
+const lines = new Map();
+const typing = new Set();
+let typingSentAt = 0;
+let lastRead = 0;
+// Set by room_mode events, commands still go through so moderators can open the room up again
+let canPost = true;
+
+joinForm.addEventListener("submit", (event) => {
+  event.preventDefault();
+  const details = new FormData(joinForm);
+  const query = new URLSearchParams();
+  for (const [key, value] of details) {
+    if (value !== "") {
+      query.set(key, value);
+    }
+  }
+  username = details.get("username");
+  connect(details.get("room_id"), query);
+});
+
+leaveButton.addEventListener("click", () => {
+  if (socket) {
+    socket.close(1000, "Left the room");
+  }
+});
+
+sendForm.addEventListener("submit", (event) => {
+  event.preventDefault();
+  const input = sendForm.elements.content;
+  const content = input.value.trim();
+  if (content === "" || !socket || socket.readyState !== WebSocket.OPEN) {
+    return;
+  }
+  if (content.startsWith("/")) {
+    socket.send(content);
+  } else if (!canPost) {
+    addNote("This room is read only, only moderators can post");
+    return;
+  } else {
+    socket.send(JSON.stringify({ type: "send", client_id: crypto.randomUUID(), content }));
+  }
+  sendTyping(false);
+  input.value = "";
+});
+
+sendForm.elements.content.addEventListener("input", (event) => {
+  const active = event.target.value !== "";
+  if (!active || Date.now() - typingSentAt > TYPING_REFRESH_MS) {
+    sendTyping(active);
+  }
+});
+
+function sendTyping(active) {
+  if (socket && socket.readyState === WebSocket.OPEN) {
+    typingSentAt = active ? Date.now() : 0;
+    socket.send(JSON.stringify({ type: "typing", active }));
+  }
+}
+
+function connect(roomId, query) {
+  const scheme = location.protocol === "https:" ? "wss" : "ws";
+  socket = new WebSocket(`${scheme}://${location.host}/ws/joinroom?${query}`);
+  messages.replaceChildren();
+  lines.clear();
+  typing.clear();
+  lastRead = 0;
+  setMode(true, null);
+
+  socket.addEventListener("open", () => {
+    roomTitle.textContent = roomId;
+    joinForm.hidden = true;
+    room.hidden = false;
+    sendForm.elements.content.focus();
+  });
+  socket.addEventListener("message", (event) => {
+    let data;
+    try {
+      data = JSON.parse(event.data);
+    } catch {
+      return;
+    }
+    handle(data);
+  });
+  socket.addEventListener("close", (event) => {
+    if (event.reason) {
+      alert(`Disconnected: ${event.reason}`);
+    }
+    socket = null;
+    room.hidden = true;
+    joinForm.hidden = false;
+  });
+}
+
+function handle(event) {
+  switch (event.type) {
+    case "message":
+      showMessage(event);
+      markRead(event.seq);
+      break;
+    case "message_edited":
+    case "message_deleted":
+      showMessage(event);
+      break;
+    case "direct":
+      addLine(`${event.sender} → ${event.recipient}`, event.content, "direct");
+      break;
+    case "mention":
+      if (lines.has(event.id)) {
+        lines.get(event.id).classList.add("mention");
+      }
+      break;
+    case "typing":
+      if (event.active) {
+        typing.add(event.user);
+      } else {
+        typing.delete(event.user);
+      }
+      showTyping();
+      break;
+    case "thread_updated":
+      if (lines.has(event.message_id)) {
+        setNote(lines.get(event.message_id), `${event.reply_count} replies`);
+      }
+      break;
+    case "room_mode":
+      setMode(event.can_post, event.slow_mode_secs);
+      break;
+    case "rejected":
+      addNote(`Not sent: ${event.reason}`);
+      break;
+    case "closing":
+      addNote(event.reason);
+      break;
+  }
+}
+
+function setMode(allowed, slowModeSecs) {
+  canPost = allowed;
+  const input = sendForm.elements.content;
+  input.classList.toggle("read-only", !allowed);
+  if (!allowed) {
+    input.placeholder = "Read only, only moderators can post";
+  } else if (slowModeSecs) {
+    input.placeholder = `Slow mode, one message every ${slowModeSecs}s`;
+  } else {
+    input.placeholder = DEFAULT_PLACEHOLDER;
+  }
+}
+
+function showMessage(msg) {
+  const line = lines.get(msg.id) || document.createElement("li");
+  line.replaceChildren();
+  line.className = msg.kind || "chat";
+  if (msg.mentions && msg.mentions.includes(username)) {
+    line.classList.add("mention");
+  }
+
+  if (msg.deleted) {
+    line.classList.add("deleted");
+    line.textContent = "[message deleted]";
+  } else if (msg.kind === "action") {
+    line.textContent = `* ${msg.sender} ${msg.content}`;
+  } else if (msg.kind === "system") {
+    line.textContent = msg.content;
+  } else {
+    const sender = document.createElement("span");
+    sender.className = "sender";
+    sender.textContent = msg.reply_to ? `↳ ${msg.sender}` : msg.sender;
+    if (msg.bot) {
+      const badge = document.createElement("span");
+      badge.className = "bot-badge";
+      badge.textContent = "bot";
+      sender.append(badge);
+    }
+    line.append(sender, msg.content);
+  }
+  if (msg.edits && msg.edits.length > 0 && !msg.deleted) {
+    setNote(line, "(edited)");
+  }
+  if (msg.reply_count) {
+    setNote(line, `${msg.reply_count} replies`);
+  }
+
+  if (!lines.has(msg.id)) {
+    lines.set(msg.id, line);
+    append(line);
+  }
+}
+
+function setNote(line, text) {
+  let note = line.querySelector(".note");
+  if (!note) {
+    note = document.createElement("span");
+    note.className = "note";
+    line.append(note);
+  }
+  note.textContent = text;
+}
+
+function addLine(sender, content, className) {
+  const line = document.createElement("li");
+  line.className = className;
+  const name = document.createElement("span");
+  name.className = "sender";
+  name.textContent = sender;
+  line.append(name, content);
+  append(line);
+}
+
+function addNote(content) {
+  const line = document.createElement("li");
+  line.className = "system";
+  line.textContent = content;
+  append(line);
+}
+
+function append(line) {
+  const atBottom = messages.scrollHeight - messages.scrollTop - messages.clientHeight < 40;
+  messages.append(line);
+  if (atBottom) {
+    messages.scrollTop = messages.scrollHeight;
+  }
+}
+
+function showTyping() {
+  const names = [...typing];
+  if (names.length === 0) {
+    typingLine.textContent = "";
+  } else if (names.length === 1) {
+    typingLine.textContent = `${names[0]} is typing…`;
+  } else {
+    typingLine.textContent = `${names.join(", ")} are typing…`;
+  }
+}
+
+// Only messages seen while the tab is visible count as read
+function markRead(seq) {
+  if (!seq || seq <= lastRead || document.hidden) {
+    return;
+  }
+  lastRead = seq;
+  if (socket && socket.readyState === WebSocket.OPEN) {
+    socket.send(JSON.stringify({ type: "read", seq }));
+  }
+}
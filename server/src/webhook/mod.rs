@@ -0,0 +1,596 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::web;
+use hmac::{Hmac, Mac};
+use mongodb::{
+    Collection, Database,
+    bson::{DateTime, Uuid},
+};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::mpsc::{self, Sender};
+
+use crate::{Err, message::Message};
+
+pub const DEAD_LETTER_COLLECTION: &str = "webhook_dead_letters";
+pub const MAX_WEBHOOKS_PER_ROOM: usize = 5;
+// Tries per delivery before it goes to the dead letter log
+pub const MAX_ATTEMPTS: u32 = 5;
+// Doubled after every failed try
+pub const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Set to true to let webhooks reach loopback, private and link-local addresses
+pub const ALLOW_INTERNAL_VAR: &str = "WEBHOOK_ALLOW_INTERNAL";
+
+// Where a room's events are posted. The secret signs every payload so the receiver can tell
+// it came from this server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: String,
+    pub created_by: String,
+}
+
+impl Webhook {
+    pub fn new(url: String, created_by: &str) -> Webhook {
+        Webhook {
+            url,
+            secret: hex::encode(rand::random::<[u8; 32]>()),
+            created_by: created_by.to_string(),
+        }
+    }
+}
+
+// What happened in the room, tagged with "type" like the events sent to users
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    // Kept under its own key so its id and room do not clash with the payload's
+    Message {
+        message: Arc<Message>,
+    },
+    // Moderated when someone other than the author made the change
+    MessageEdited {
+        message: Arc<Message>,
+        by: Arc<String>,
+        moderated: bool,
+    },
+    MessageDeleted {
+        message: Arc<Message>,
+        by: Arc<String>,
+        moderated: bool,
+    },
+    Joined {
+        account: Arc<String>,
+        username: Arc<String>,
+    },
+    Left {
+        account: Arc<String>,
+        username: Arc<String>,
+    },
+    // Taken out of the room by an admin, followed by left
+    Removed {
+        account: Arc<String>,
+        username: Arc<String>,
+        reason: String,
+    },
+}
+
+impl WebhookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::Message { .. } => "message",
+            WebhookEvent::MessageEdited { .. } => "message_edited",
+            WebhookEvent::MessageDeleted { .. } => "message_deleted",
+            WebhookEvent::Joined { .. } => "joined",
+            WebhookEvent::Left { .. } => "left",
+            WebhookEvent::Removed { .. } => "removed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: String,
+    room_id: &'a str,
+    // Milliseconds since the unix epoch
+    sent_at: i64,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+#[derive(Debug)]
+struct Delivery {
+    id: String,
+    room_id: String,
+    event_type: &'static str,
+    url: String,
+    secret: String,
+    body: String,
+}
+
+// A delivery that failed every try, kept so it can be looked into or sent again by hand
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeadLetter {
+    pub delivery_id: String,
+    pub room_id: String,
+    pub event_type: String,
+    pub url: String,
+    pub body: String,
+    pub attempts: u32,
+    pub last_error: String,
+    // Milliseconds since the unix epoch
+    pub failed_at: i64,
+}
+
+/*
+ * Posts room events to their webhooks from a task of its own, so a slow or broken receiver
+ * never holds up a room. Deliveries run side by side and can arrive out of order.
+ */
+#[derive(Debug, Clone)]
+pub struct WebhookDispatcher {
+    sender: Sender<Delivery>,
+    // Off unless the operator opts in, webhooks could otherwise probe the internal network
+    allow_internal: bool,
+}
+
+impl WebhookDispatcher {
+    pub fn spawn(database: web::Data<Database>) -> WebhookDispatcher {
+        let allow_internal = std::env::var(ALLOW_INTERNAL_VAR).is_ok_and(|allow| allow == "true");
+        if allow_internal {
+            println!("Webhooks may reach internal addresses, {ALLOW_INTERNAL_VAR} is set");
+        }
+        let (delivery_tx, mut delivery_rx) = mpsc::channel::<Delivery>(1000);
+        let client = client(allow_internal);
+        tokio::spawn(async move {
+            while let Some(delivery) = delivery_rx.recv().await {
+                let client = client.clone();
+                let database = database.clone();
+                tokio::spawn(async move {
+                    if let Some(dead_letter) =
+                        deliver(&client, &delivery, allow_internal, FIRST_RETRY_DELAY).await
+                    {
+                        store_dead_letter(&database, dead_letter).await;
+                    }
+                });
+            }
+        });
+        WebhookDispatcher {
+            sender: delivery_tx,
+            allow_internal,
+        }
+    }
+
+    /*
+     * Refuses urls that are not http(s) or that name an internal host outright. Names that
+     * only resolve to internal addresses are caught when delivering.
+     */
+    pub fn check_url(&self, url: &str) -> Result<(), String> {
+        let parsed = reqwest::Url::parse(url)
+            .ok()
+            .filter(|parsed| matches!(parsed.scheme(), "http" | "https"))
+            .ok_or_else(|| format!("{url} is not an http or https url"))?;
+        if !self.allow_internal && is_internal_host(&parsed) {
+            return Err(format!(
+                "{url} is an internal address, webhooks can only reach public hosts"
+            ));
+        }
+        Ok(())
+    }
+
+    /*
+     * Queues the event for every webhook of the room without waiting, rooms call this while
+     * locked. Events that do not fit in the queue are dropped.
+     */
+    pub fn queue(&self, room_id: &str, webhooks: &[Webhook], event: WebhookEvent) {
+        let id = Uuid::new().to_string();
+        let payload = Payload {
+            id: id.clone(),
+            room_id,
+            sent_at: DateTime::now().timestamp_millis(),
+            event: &event,
+        };
+        let body = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                println!("Unable to serialize webhook event {e:?}");
+                return;
+            }
+        };
+        for webhook in webhooks {
+            let delivery = Delivery {
+                id: id.clone(),
+                room_id: room_id.to_string(),
+                event_type: event.name(),
+                url: webhook.url.clone(),
+                secret: webhook.secret.clone(),
+                body: body.clone(),
+            };
+            if let Err(e) = self.sender.try_send(delivery) {
+                println!("Dropping webhook delivery, the queue is full or stopped {e:?}");
+            }
+        }
+    }
+}
+
+/*
+ * Hex HMAC-SHA256 of "<timestamp>.<body>". Receivers recompute it with the webhook secret and
+ * the X-Webhook-Timestamp header, which also lets them turn away old replayed requests.
+ */
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/*
+ * Loopback, private, link-local, carrier-grade NAT and unspecified addresses, including IPv4
+ * addresses mapped into IPv6
+ */
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_internal(IpAddr::V4(mapped)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+fn is_internal_host(url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return true;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_internal(ip),
+        Err(_) => host == "localhost" || host.ends_with(".localhost"),
+    }
+}
+
+// Resolves webhook hosts and leaves out internal addresses, so a public name pointing inside
+// the network is refused when connecting rather than only when the webhook is added
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| !is_internal(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{host} only resolves to internal addresses").into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/*
+ * Redirects are never followed since they could point anywhere. Proxies are skipped as well
+ * when guarding, they would resolve the host themselves.
+ */
+fn client(allow_internal: bool) -> reqwest::Client {
+    let builder = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(Policy::none());
+    let builder = match allow_internal {
+        true => builder,
+        false => builder.no_proxy().dns_resolver(Arc::new(PublicResolver)),
+    };
+    builder.build().unwrap_or_default()
+}
+
+async fn post(
+    client: &reqwest::Client,
+    delivery: &Delivery,
+    allow_internal: bool,
+) -> Result<(), Err> {
+    // Addresses written into the url never reach the resolver
+    let url = reqwest::Url::parse(&delivery.url)?;
+    if !allow_internal && is_internal_host(&url) {
+        return Err(format!("{} is an internal address", delivery.url).into());
+    }
+    let timestamp = DateTime::now().timestamp_millis();
+    let signature = sign(&delivery.secret, timestamp, &delivery.body);
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Webhook-Id", &delivery.id)
+        .header("X-Webhook-Event", delivery.event_type)
+        .header("X-Webhook-Timestamp", timestamp.to_string())
+        .header("X-Webhook-Signature", format!("sha256={signature}"))
+        .body(delivery.body.clone())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("Receiver answered {}", response.status()).into());
+    }
+    Ok(())
+}
+
+/*
+ * Tries the delivery up to MAX_ATTEMPTS times, doubling the delay between tries. Hands back
+ * the dead letter when every try failed.
+ */
+async fn deliver(
+    client: &reqwest::Client,
+    delivery: &Delivery,
+    allow_internal: bool,
+    first_retry_delay: Duration,
+) -> Option<DeadLetter> {
+    let mut delay = first_retry_delay;
+    let mut last_error = String::new();
+    for attempt in 1..=MAX_ATTEMPTS {
+        match post(client, delivery, allow_internal).await {
+            Ok(()) => return None,
+            Err(e) => {
+                println!(
+                    "Webhook delivery {} to {} failed on try {attempt} {e:?}",
+                    delivery.id, delivery.url
+                );
+                last_error = e.to_string();
+            }
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
+    println!(
+        "Giving up on webhook delivery {} to {}",
+        delivery.id, delivery.url
+    );
+    Some(DeadLetter {
+        delivery_id: delivery.id.clone(),
+        room_id: delivery.room_id.clone(),
+        event_type: delivery.event_type.to_string(),
+        url: delivery.url.clone(),
+        body: delivery.body.clone(),
+        attempts: MAX_ATTEMPTS,
+        last_error,
+        failed_at: DateTime::now().timestamp_millis(),
+    })
+}
+
+async fn store_dead_letter(database: &Database, dead_letter: DeadLetter) {
+    let collection: Collection<DeadLetter> = database.collection(DEAD_LETTER_COLLECTION);
+    collection
+        .insert_one(&dead_letter)
+        .await
+        .map(|_| ())
+        .unwrap_or_else(|e| println!("Unable to store dead letter {dead_letter:?} {e:?}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc::UnboundedReceiver,
+    };
+
+    use super::*;
+
+    const SHORT_DELAY: Duration = Duration::from_millis(1);
+
+    struct Received {
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    /*
+     * Receiver on a local port that answers the first failures requests with 500 and the rest
+     * with 200, passing on every request it got
+     */
+    async fn stub(failures: usize) -> (String, UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (received_tx, received_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut answered = 0;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                let (head_len, headers) = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let headers: HashMap<String, String> = text[..end]
+                            .lines()
+                            .skip(1)
+                            .filter_map(|line| line.split_once(": "))
+                            .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+                            .collect();
+                        break (end + 4, headers);
+                    }
+                };
+                let length: usize = headers["content-length"].parse().unwrap();
+                while request.len() < head_len + length {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let body = String::from_utf8_lossy(&request[head_len..]).to_string();
+                let status = match answered < failures {
+                    true => "500 Internal Server Error",
+                    false => "200 OK",
+                };
+                answered += 1;
+                let response =
+                    format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+                received_tx.send(Received { headers, body }).unwrap();
+            }
+        });
+        (url, received_rx)
+    }
+
+    fn delivery(url: String) -> Delivery {
+        Delivery {
+            id: "delivery".to_string(),
+            room_id: "lobby".to_string(),
+            event_type: "message",
+            url,
+            secret: "secret".to_string(),
+            body: r#"{"type":"message"}"#.to_string(),
+        }
+    }
+
+    fn dispatcher(allow_internal: bool) -> WebhookDispatcher {
+        WebhookDispatcher {
+            sender: mpsc::channel(1).0,
+            allow_internal,
+        }
+    }
+
+    #[actix_web::test]
+    async fn retries_until_delivered_with_a_valid_signature() {
+        let (url, mut received) = stub(2).await;
+        let delivery = delivery(url);
+
+        let dead_letter = deliver(&client(true), &delivery, true, SHORT_DELAY).await;
+
+        assert!(dead_letter.is_none());
+        for _ in 0..3 {
+            let request = received.recv().await.unwrap();
+            assert_eq!(request.body, delivery.body);
+            assert_eq!(request.headers["x-webhook-id"], "delivery");
+            assert_eq!(request.headers["x-webhook-event"], "message");
+            let timestamp: i64 = request.headers["x-webhook-timestamp"].parse().unwrap();
+            let signature = sign("secret", timestamp, &delivery.body);
+            assert_eq!(
+                request.headers["x-webhook-signature"],
+                format!("sha256={signature}")
+            );
+        }
+        assert!(received.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn gives_up_with_a_dead_letter() {
+        let (url, mut received) = stub(usize::MAX).await;
+        let delivery = delivery(url.clone());
+
+        let dead_letter = deliver(&client(true), &delivery, true, SHORT_DELAY)
+            .await
+            .unwrap();
+
+        assert_eq!(dead_letter.delivery_id, "delivery");
+        assert_eq!(dead_letter.room_id, "lobby");
+        assert_eq!(dead_letter.url, url);
+        assert_eq!(dead_letter.body, delivery.body);
+        assert_eq!(dead_letter.attempts, MAX_ATTEMPTS);
+        assert!(dead_letter.last_error.contains("500"));
+        for _ in 0..MAX_ATTEMPTS {
+            received.recv().await.unwrap();
+        }
+        assert!(received.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn internal_targets_are_never_contacted() {
+        let (url, mut received) = stub(0).await;
+
+        let dead_letter = deliver(&client(false), &delivery(url), false, SHORT_DELAY)
+            .await
+            .unwrap();
+
+        assert!(dead_letter.last_error.contains("internal address"));
+        assert!(received.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn names_resolving_inside_are_refused() {
+        let resolved = PublicResolver.resolve("localhost".parse().unwrap()).await;
+        assert!(resolved.is_err());
+    }
+
+    #[test]
+    fn signature_covers_timestamp_and_body() {
+        let signature = sign("secret", 1, "body");
+        assert_eq!(signature.len(), 64);
+        assert_eq!(signature, sign("secret", 1, "body"));
+        assert_ne!(signature, sign("secret", 2, "body"));
+        assert_ne!(signature, sign("secret", 1, "other"));
+        assert_ne!(signature, sign("other", 1, "body"));
+    }
+
+    #[test]
+    fn internal_addresses_are_recognised() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_internal(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!is_internal(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn only_public_http_urls_are_accepted() {
+        let webhooks = dispatcher(false);
+        assert!(webhooks.check_url("https://example.com/hook").is_ok());
+        for url in [
+            "ftp://example.com/hook",
+            "not a url",
+            "http://127.0.0.1:8080/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://localhost/hook",
+            "http://api.localhost/hook",
+        ] {
+            assert!(webhooks.check_url(url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn operators_can_allow_internal_urls() {
+        let webhooks = dispatcher(true);
+        assert!(webhooks.check_url("http://127.0.0.1:8080/hook").is_ok());
+        assert!(webhooks.check_url("ftp://127.0.0.1/hook").is_err());
+    }
+}